@@ -0,0 +1,378 @@
+// cdenv - builtin/src/lib.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// A bash loadable builtin that runs cdenv list and compare inside the shell
+// process:
+//
+//   enable -f cdenv_core.so cdenv_core
+//   cdenv_core list [--reload] [--autoreload]
+//   cdenv_core snapshot <name> <code>
+//   cdenv_core compare <file> <before> <after>
+//
+// Instead of taking the other options and printing shell code, list reads the
+// settings, PWD and CDENV_STACK from the shell variables and assigns
+// CDENV_STACK, CDENV_TAG, unload, load, removed and changed directly, see
+// Plan::render().
+// c:update declares unload and load as local arrays before the call, so that
+// they end up in its scope.
+//
+// snapshot runs the code, e.g. c:snapshot, in the shell and keeps what it
+// prints under a name, so that no snapshot has to leave the process. compare
+// takes two of them, writes the restore file like cdenv compare does and puts
+// the shell code in __cdenv_code, which c:source declares.
+//
+// The functions from bash are resolved when the builtin is loaded. The layout
+// of a SHELL_VAR and the array functions are the same in all versions of bash
+// that cdenv supports, the array itself is only touched through them.
+
+extern crate cdenv;
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::io::FromRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+use cdenv::environment::{self, Shell};
+use cdenv::file;
+use cdenv::serve::Server;
+use cdenv::store::Store;
+use cdenv::{CompareContext, DiscoveryConfig};
+
+const EXECUTION_SUCCESS: c_int = 0;
+const EXECUTION_FAILURE: c_int = 1;
+const EX_USAGE: c_int = 258;
+const BUILTIN_ENABLED: c_int = 0x01;
+const ATT_ARRAY: c_int = 0x04;
+const SEVAL_NOHIST: c_int = 0x004;
+const SEVAL_NOFREE: c_int = 0x008;
+const O_CLOEXEC: c_int = 0o2000000;
+const F_DUPFD_CLOEXEC: c_int = 1030;
+
+const USAGE: &str = concat!("cdenv_core: usage: cdenv_core list [--reload] [--autoreload]\n",
+                            "                  cdenv_core snapshot <name> <code>\n",
+                            "                  cdenv_core compare <file> <before> <after>");
+
+#[repr(C)]
+pub struct WordDesc {
+    word: *mut c_char,
+    flags: c_int
+}
+
+#[repr(C)]
+pub struct WordList {
+    next: *mut WordList,
+    word: *mut WordDesc
+}
+
+#[repr(C)]
+struct ShellVar {
+    name: *mut c_char,
+    value: *mut c_void,
+    exportstr: *mut c_char,
+    dynamic_value: *mut c_void,
+    assign_func: *mut c_void,
+    attributes: c_int,
+    context: c_int
+}
+
+#[repr(C)]
+pub struct Builtin {
+    name: *const c_char,
+    function: extern "C" fn(*mut WordList) -> c_int,
+    flags: c_int,
+    long_doc: *const *const c_char,
+    short_doc: *const c_char,
+    handle: *mut c_char
+}
+
+extern "C" {
+    fn get_string_value(name: *const c_char) -> *mut c_char;
+    fn find_variable(name: *const c_char) -> *mut ShellVar;
+    fn find_or_make_array_variable(name: *const c_char, flags: c_int) -> *mut ShellVar;
+    fn bind_variable(name: *const c_char, value: *const c_char, flags: c_int) -> *mut ShellVar;
+    fn bind_array_variable(name: *const c_char, index: i64, value: *const c_char,
+                           flags: c_int) -> *mut ShellVar;
+    fn array_flush(array: *mut c_void);
+    fn array_to_word_list(array: *mut c_void) -> *mut WordList;
+    fn dispose_words(list: *mut WordList);
+    fn parse_and_execute(string: *mut c_char, from_file: *const c_char, flags: c_int) -> c_int;
+    static dollar_dollar_pid: c_int;
+}
+
+// From the C library that bash is linked with.
+extern "C" {
+    fn pipe2(fds: *mut c_int, flags: c_int) -> c_int;
+    fn fcntl(fd: c_int, command: c_int, arg: c_int) -> c_int;
+    fn dup2(fd: c_int, fd2: c_int) -> c_int;
+    fn close(fd: c_int) -> c_int;
+    fn fflush(stream: *mut c_void) -> c_int;
+}
+
+static mut LONG_DOC: [*const c_char; 7] = [
+    b"Run cdenv list and compare inside the shell, see cdenv.sh.\0".as_ptr() as *const c_char,
+    b"\0".as_ptr() as *const c_char,
+    b"list sets CDENV_STACK, CDENV_TAG and the unload, load, removed and changed arrays.\0".as_ptr() as *const c_char,
+    b"snapshot keeps the output of code under a name.\0".as_ptr() as *const c_char,
+    b"compare writes the restore file for a file from two snapshots and sets\0".as_ptr() as *const c_char,
+    b"__cdenv_code.\0".as_ptr() as *const c_char,
+    ptr::null()
+];
+
+#[no_mangle]
+pub static mut cdenv_core_struct: Builtin = Builtin {
+    name: b"cdenv_core\0".as_ptr() as *const c_char,
+    function: cdenv_core,
+    flags: BUILTIN_ENABLED,
+    long_doc: ptr::addr_of!(LONG_DOC) as *const *const c_char,
+    short_doc: b"cdenv_core list [--reload] [--autoreload] | snapshot name code | compare file before after\0"
+        .as_ptr() as *const c_char,
+    handle: ptr::null_mut()
+};
+
+// The builtin keeps what cdenv serve keeps, the contents of the CDENV_PATH
+// directories and the snapshots, for the lifetime of the shell.
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+
+extern "C" fn cdenv_core(list: *mut WordList) -> c_int {
+    let args = unsafe { words(list) };
+    let args: Vec<&str> = args.iter().map(|x| x.as_str()).collect();
+
+    let run: Box<dyn Fn() -> Result<(), String>> = match args.as_slice() {
+        ["list", options @ ..] if options.iter().all(|x| *x == "--reload" || *x == "--autoreload") => {
+            let reload = options.contains(&"--reload");
+            let autoreload = options.contains(&"--autoreload");
+            Box::new(move || {
+                list_paths(reload, autoreload);
+                Ok(())
+            })
+        },
+        ["snapshot", name, code] => Box::new(move || take_snapshot(name, code)),
+        ["compare", file, before, after] => Box::new(move || compare(file, before, after)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return EX_USAGE;
+        }
+    };
+
+    // A panic must not unwind into bash. The message has already been printed
+    // by then.
+    match panic::catch_unwind(AssertUnwindSafe(run)) {
+        Ok(Ok(())) => EXECUTION_SUCCESS,
+        Ok(Err(message)) => {
+            eprintln!("cdenv_core: {}", message);
+            EXECUTION_FAILURE
+        },
+        Err(_) => EXECUTION_FAILURE
+    }
+}
+
+fn server() -> MutexGuard<'static, Option<Server>> {
+    SERVER.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+// The process id of the shell, which is $$ in subshells as well.
+fn shell_pid() -> String {
+    unsafe { dollar_dollar_pid.to_string() }
+}
+
+// The builtin version of cdenv list, see file::list_code().
+fn list_paths(reload: bool, autoreload: bool) {
+    let tag_str = string("CDENV_TAG");
+    let config = DiscoveryConfig {
+        global: string("CDENV_GLOBAL") == "1",
+        reload,
+        autoreload,
+        tag: match tag_str.parse() {
+            Ok(number) => { number },
+            Err(_) => panic!("invalid number {:?}", tag_str),
+        },
+        file: string("CDENV_FILE"),
+        path: string("CDENV_PATH"),
+        home: string("HOME"),
+        shell: Shell::Bash
+    };
+    let log = string("CDENV_LOG");
+    let cache = string("CDENV_CACHE");
+
+    let mut server = server();
+    let plan = file::list_plan(&config, &string("PWD"), &array("CDENV_STACK"),
+                               Some(log.as_str()).filter(|log| !log.is_empty()),
+                               &shell_pid(),
+                               Some(cache.as_str()).filter(|cache| !cache.is_empty()),
+                               &mut server.get_or_insert_with(Server::default).libraries);
+
+    set_array("CDENV_STACK", &plan.stack);
+    if !config.reload && config.autoreload {
+        set_array("removed", &plan.removed);
+        set_array("changed", &plan.changed);
+    }
+    set_array("unload", &plan.unload.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>());
+    set_array("load", &plan.load.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>());
+    if !config.reload && config.autoreload {
+        set_string("CDENV_TAG", &plan.tag.to_string());
+    }
+}
+
+// Run the code in the shell and keep its output as a snapshot, see
+// Server::put().
+fn take_snapshot(name: &str, code: &str) -> Result<(), String> {
+    let text = capture(code).map_err(|err| format!("unable to take the snapshot: {}", err))?;
+    server().get_or_insert_with(Server::default).put(name, &text);
+    Ok(())
+}
+
+// The builtin version of cdenv compare. The options come from the shell
+// variables, the same ones that c:source passes to the executable.
+fn compare(file: &str, before: &str, after: &str) -> Result<(), String> {
+    let (env_a, env_b) = {
+        let mut server = server();
+        let server = server.get_or_insert_with(Server::default);
+        (server.take(before)?, server.take(after)?)
+    };
+    let shadow = match string("CDENV_SHADOW_LEVEL").as_str() {
+        "" => 1,
+        level => level.parse().map_err(|_| format!("invalid CDENV_SHADOW_LEVEL {:?}", level))?
+    };
+    let pid = shell_pid();
+    let context = CompareContext {
+        store: Store::new(&format!("{}/{}", string("CDENV_CACHE"), pid)),
+        file: file.to_string(),
+        redact: Some(string("CDENV_REDACT")).filter(|redact| !redact.is_empty()),
+        log: Some(string("CDENV_LOG")).filter(|log| !log.is_empty()),
+        pid,
+        loaded: array("CDENV_STACK"),
+        shadow
+    };
+    let code = environment::compare_snapshots(context, env_a, env_b).map_err(|err| err.to_string())?;
+    set_string("__cdenv_code", &code);
+    Ok(())
+}
+
+// Run shell code in this shell and return what it writes to stdout. A thread
+// reads the output while it is written, so that it may exceed the size of the
+// pipe buffer.
+fn capture(code: &str) -> io::Result<String> {
+    let code = CString::new(code).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut fds: [c_int; 2] = [-1, -1];
+    unsafe {
+        if pipe2(fds.as_mut_ptr(), O_CLOEXEC) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut reader = File::from_raw_fd(fds[0]);
+        let thread = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut data = vec![];
+            reader.read_to_end(&mut data)?;
+            Ok(data)
+        });
+
+        // Whatever bash has buffered for stdout so far belongs to the
+        // terminal, not to the snapshot.
+        fflush(ptr::null_mut());
+        let saved = fcntl(1, F_DUPFD_CLOEXEC, 0);
+        if saved < 0 || dup2(fds[1], 1) < 0 {
+            let err = io::Error::last_os_error();
+            close(fds[1]);
+            if saved >= 0 {
+                close(saved);
+            }
+            let _ = thread.join();
+            return Err(err);
+        }
+        close(fds[1]);
+
+        // bash frees the string itself unless it is told not to.
+        let raw = code.into_raw();
+        parse_and_execute(raw, b"cdenv_core\0".as_ptr() as *const c_char, SEVAL_NOHIST | SEVAL_NOFREE);
+        drop(CString::from_raw(raw));
+
+        // Restoring stdout closes the last end of the pipe that is written to.
+        fflush(ptr::null_mut());
+        dup2(saved, 1);
+        close(saved);
+        let data = thread.join().map_err(|_| io::Error::other("the reader has failed"))??;
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+}
+
+unsafe fn words(mut list: *mut WordList) -> Vec<String> {
+    let mut words = vec![];
+    while !list.is_null() {
+        words.push(CStr::from_ptr((*(*list).word).word).to_string_lossy().to_string());
+        list = (*list).next;
+    }
+    words
+}
+
+// Return the value of a shell variable, unset variables are empty.
+fn string(name: &str) -> String {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        let value = get_string_value(name.as_ptr());
+        if value.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(value).to_string_lossy().to_string()
+        }
+    }
+}
+
+// Return the values of an array variable.
+fn array(name: &str) -> Vec<String> {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        let var = find_variable(name.as_ptr());
+        if var.is_null() || (*var).attributes & ATT_ARRAY == 0 {
+            return vec![];
+        }
+        let list = array_to_word_list((*var).value);
+        let values = words(list);
+        dispose_words(list);
+        values
+    }
+}
+
+fn set_string(name: &str, value: &str) {
+    let name = CString::new(name).unwrap();
+    let value = CString::new(value).unwrap();
+    unsafe {
+        if bind_variable(name.as_ptr(), value.as_ptr(), 0).is_null() {
+            panic!("unable to set {:?}", name);
+        }
+    }
+}
+
+// Replace the values of an array variable in the scope where it is found, or
+// in the global scope.
+fn set_array(name: &str, values: &[String]) {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        let var = find_or_make_array_variable(name.as_ptr(), 1);
+        if var.is_null() || (*var).attributes & ATT_ARRAY == 0 {
+            panic!("unable to set {:?}", name);
+        }
+        array_flush((*var).value);
+        for (index, value) in values.iter().enumerate() {
+            let value = CString::new(value.as_str()).unwrap();
+            bind_array_variable(name.as_ptr(), index as i64, value.as_ptr(), 0);
+        }
+    }
+}
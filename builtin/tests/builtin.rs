@@ -0,0 +1,77 @@
+// cdenv - builtin/tests/builtin.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Load the builtin into a clean bash and walk through a directory tree.
+
+use std::fs;
+use std::process::Command;
+
+// The shared library is built next to the deps directory with the test
+// executable.
+fn library() -> String {
+    let exe = std::env::current_exe().unwrap();
+    exe.parent().unwrap().parent().unwrap().join("libcdenv_core.so").display().to_string()
+}
+
+#[test]
+fn list_sets_the_arrays() {
+    let root = std::env::temp_dir().join(format!("cdenv-test-builtin-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    for dir in &["home/proj/sub", "libs"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    for file in &["home/proj/.cdenv.sh", "home/proj/sub/.cdenv.sh", "libs/lib.sh"] {
+        fs::write(root.join(file), "").unwrap();
+    }
+
+    let output = Command::new("bash")
+        .args(["--norc", "--noprofile", "-c", r#"
+enable -f "$1" cdenv_core
+CDENV_GLOBAL=1 CDENV_FILE=.cdenv.sh CDENV_PATH="$PWD/libs" CDENV_TAG=0 CDENV_LOG= CDENV_CACHE=
+declare -a CDENV_STACK=()
+update() {
+    local -a unload=() load=()
+    cdenv_core list "$@"
+    echo "unload ${unload[*]#$root/}"
+    echo "load ${load[*]#$root/}"
+}
+root=$PWD
+cd home/proj/sub && update
+cd .. && update
+update --reload
+echo "stack ${CDENV_STACK[*]#$root/}"
+cdenv_core list --bogus 2>/dev/null || echo "usage $?"
+"#, "bash", &library()])
+        .current_dir(&root)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap())
+        .env("HOME", root.join("home"))
+        .output()
+        .unwrap();
+    let _ = fs::remove_dir_all(&root);
+
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+               "unload \n\
+                load libs/lib.sh home/proj/.cdenv.sh home/proj/sub/.cdenv.sh\n\
+                unload home/proj/sub/.cdenv.sh\n\
+                load \n\
+                unload home/proj/.cdenv.sh libs/lib.sh\n\
+                load libs/lib.sh home/proj/.cdenv.sh\n\
+                stack libs/lib.sh home/proj/.cdenv.sh\n\
+                usage 2\n");
+}
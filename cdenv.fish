@@ -0,0 +1,425 @@
+#
+# cdenv - cdenv.fish
+#
+# Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+#
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU General Public License as published by
+# the Free Software Foundation, either version 3 of the License, or
+# (at your option) any later version.
+#
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU General Public License for more details.
+#
+# You should have received a copy of the GNU General Public License
+# along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+# The fish counterpart of cdenv.sh. It drives the same list and compare
+# commands, only the snapshots and the restore files use fish syntax, see
+# src/fish.rs for the snapshot format.
+
+set -g CDENV_RCFILE .cdenvrc.fish
+
+# Defaults.
+set -g CDENV_VERBOSE 0
+set -g CDENV_GLOBAL 1
+set -g CDENV_FILE .cdenv.fish
+set -g CDENV_FISH (realpath (status current-filename))
+set -g CDENV_EXEC (dirname $CDENV_FISH)/cdenv
+# The libraries in libs are written for bash.
+set -g CDENV_PATH ''
+if set -q XDG_RUNTIME_DIR
+    set -g CDENV_CACHE $XDG_RUNTIME_DIR/cdenv
+else
+    # ~/.cache may be shared with other hosts.
+    set -g CDENV_CACHE $HOME/.cache/cdenv/(uname -n)
+end
+if set -q XDG_STATE_HOME
+    set -g CDENV_LOG $XDG_STATE_HOME/cdenv/log
+else
+    set -g CDENV_LOG $HOME/.local/state/cdenv/log
+end
+set -g CDENV_CALLBACK
+set -g CDENV_STACK
+set -g CDENV_AUTORELOAD 0
+set -g CDENV_TAG 0
+set -g CDENV_REDACT ''
+set -g CDENV_SHADOW_LEVEL 1
+
+set -g CDENV_COLOR 1
+set -g CDENV_COLOR_ERR (set_color red)
+set -g CDENV_COLOR_WARN (set_color yellow)
+set -g CDENV_COLOR_MSG (set_color blue)
+set -g CDENV_COLOR_DEBUG (set_color brblack)
+set -g CDENV_COLOR_RESET (set_color normal)
+
+# Variables that fish maintains itself and that cannot be set.
+set -g __cdenv_skip _ argv CMD_DURATION COLUMNS FISH_VERSION fish_kill_signal fish_pid \
+    fish_private_mode history hostname last_pid LINES pipestatus PWD SHLVL status \
+    status_generation umask version
+
+# Load the settings file selectively replacing the defaults from above.
+test -e $HOME/$CDENV_RCFILE; and source $HOME/$CDENV_RCFILE
+
+# Create a directory for the restore files which will be removed when the
+# shell exits. The restore files contain the values of variables, so nobody
+# else must be able to read them. Directories left behind by shells that did
+# not exit cleanly are removed along the way. If the directory belongs to a
+# shell on another host that shares the cache directory, cdenv is not
+# activated.
+set -g __cdenv_error ($CDENV_EXEC gc --cache="$CDENV_CACHE" --pid=$fish_pid 2>&1)
+
+function c:exit --on-event fish_exit
+    set -q __cdenv_error[1]; or rm -r "$CDENV_CACHE/$fish_pid"
+    for cb in $CDENV_EXIT_CALLBACK
+        $cb
+    end
+end
+
+# Switch off all colors if requested.
+if not isatty stdout; or test "$CDENV_COLOR" -ne 1
+    set -e CDENV_COLOR_ERR
+    set -e CDENV_COLOR_WARN
+    set -e CDENV_COLOR_MSG
+    set -e CDENV_COLOR_DEBUG
+    set -e CDENV_COLOR_RESET
+end
+
+function c.err
+    # Print an error message to stderr.
+    echo "$CDENV_COLOR_ERR""ERROR: $argv$CDENV_COLOR_RESET" >&2
+end
+
+function c.warn
+    # Print a warning message to stderr.
+    echo "$CDENV_COLOR_WARN""WARNING: $argv$CDENV_COLOR_RESET" >&2
+end
+
+function c:leak
+    # Report a change to the shell state that cannot be undone.
+    echo "$CDENV_COLOR_WARN""LEAK: $argv$CDENV_COLOR_RESET" >&2
+end
+
+function c.msg
+    # Print a message to stderr.
+    test "$CDENV_VERBOSE" -ge 1; and echo "$CDENV_COLOR_MSG$argv$CDENV_COLOR_RESET" >&2
+end
+
+function c.debug
+    # Print a debug message to stderr.
+    test "$CDENV_VERBOSE" -ge 2; and echo "$CDENV_COLOR_DEBUG""cdenv: $argv$CDENV_COLOR_RESET" >&2
+end
+
+function c.translate
+    # Translate /home/user/foo to ~/foo.
+    set -l name (realpath --relative-base $HOME $argv[1])
+    if string match -q '/*' -- $name
+        echo $name
+    else if test $name = .
+        echo \~
+    else
+        echo \~/$name
+    end
+end
+
+function c:store
+    # Access the restore files in $CDENV_CACHE/<pid>, see 'cdenv store'.
+    $CDENV_EXEC store --dir="$CDENV_CACHE/$fish_pid" $argv
+end
+
+function c:safe_source
+    # Source a file in the context of a specific directory. builtin cd leaves
+    # dirprev alone. Try to avoid collisions with names from the sources.
+    set -l __cdenv_directory $argv[1]
+    set -l __cdenv_file $argv[2]
+    set -l __cdenv_savedir $PWD
+
+    builtin cd $__cdenv_directory; or return 1
+    # Check the script for errors before sourcing.
+    if fish --no-execute $__cdenv_file
+        source $__cdenv_file
+    end
+    # The file is sourced inside this function, where set without a scope
+    # creates variables that vanish when it returns. Make them global, as if
+    # the file had been sourced at the top level.
+    for __cdenv_name in (set --local --names)
+        string match -q -r '^(__cdenv_|argv$)' -- $__cdenv_name; and continue
+        if set -q -x $__cdenv_name
+            set -gx $__cdenv_name $$__cdenv_name
+        else
+            set -g $__cdenv_name $$__cdenv_name
+        end
+    end
+    # Remember where the file left us, see c:state.
+    set -g __cdenv_pwd $PWD
+    builtin cd $__cdenv_savedir; or return 1
+end
+
+function c:update
+    # The working directory changes while the files are sourced, c:on_pwd
+    # must not start another update then.
+    set -g __cdenv_busy 1
+
+    set -l args
+    test "$argv[1]" = reload; and set -a args --reload
+    test "$CDENV_AUTORELOAD" -eq 1; and set -a args --autoreload
+
+    # list sets CDENV_STACK and the __cdenv_* lists used below.
+    $CDENV_EXEC list --shell=fish --global=$CDENV_GLOBAL --path="$CDENV_PATH" --file=$CDENV_FILE --tag=$CDENV_TAG --log="$CDENV_LOG" --pid=$fish_pid --cache="$CDENV_CACHE" $args $PWD $CDENV_STACK | source
+
+    set -l file
+    for file in $__cdenv_removed
+        c.msg (c.translate $file)" was removed"
+    end
+    for file in $__cdenv_changed
+        c.msg (c.translate $file)" was changed"
+    end
+
+    # First undo the changes made to the environment.
+    for file in $__cdenv_unload
+        c:unsource $file
+    end
+
+    if test "$argv[1]" = reload
+        # Reload the settings file.
+        if test -e $HOME/$CDENV_RCFILE
+            c.msg "reloading ~/$CDENV_RCFILE"
+            if fish --no-execute $HOME/$CDENV_RCFILE
+                source $HOME/$CDENV_RCFILE
+            end
+        end
+        # Reload this fish module.
+        c.msg "reloading "(c.translate $CDENV_FISH)
+        source $CDENV_FISH
+    end
+
+    # Source the needed cdenv files.
+    for file in $__cdenv_load
+        c:source $file
+    end
+
+    set -e -g __cdenv_removed __cdenv_changed __cdenv_unload __cdenv_load __cdenv_busy
+end
+
+function c:unsource --argument-names file
+    # Undo the changes from a single cdenv file.
+    c.msg "unsource "(c.translate $file)
+    c:store read $file | source
+    c:store remove $file
+end
+
+function c:source --argument-names __cdenv_file
+    # Source a single cdenv file and keep track of the changes to the
+    # environment. Try to avoid collisions with names from the sources.
+
+    # Save a snapshot of the environment. fish has no process substitution,
+    # so it goes to a file next to the restore files.
+    set -l __cdenv_before (mktemp "$CDENV_CACHE/$fish_pid/snapshot.XXXXXX")
+    begin
+        c:snapshot
+        c:state
+    end >$__cdenv_before
+
+    # Source the cdenv file.
+    c.msg "source "(c.translate $__cdenv_file)
+    c:safe_source (dirname $__cdenv_file) $__cdenv_file
+
+    # Save another snapshot of the environment and compare both. Create a
+    # restore file that can be used to undo all changes to the environment when
+    # changing to another directory.
+    begin
+        c:snapshot
+        c:state
+    end | $CDENV_EXEC compare --log="$CDENV_LOG" --pid=$fish_pid --file=$__cdenv_file --redact="$CDENV_REDACT" --store="$CDENV_CACHE/$fish_pid" --shadow="$CDENV_SHADOW_LEVEL" $__cdenv_before $CDENV_STACK | source
+
+    rm -f $__cdenv_before
+    set -e -g __cdenv_pwd
+end
+
+function c:snapshot
+    # Print the global variables, functions and abbreviations with their names
+    # and values percent-encoded. The variables of fish itself and of cdenv are
+    # left out.
+    echo "# cdenv fish $version"
+    set -l __cdenv_name
+    for __cdenv_name in (set --global --names)
+        contains -- $__cdenv_name $__cdenv_skip; and continue
+        string match -q -r '^__(fish|cdenv)' -- $__cdenv_name; and continue
+        set -l __cdenv_flags -g
+        set -q -x $__cdenv_name; and set __cdenv_flags -gx
+        echo var $__cdenv_name $__cdenv_flags "="(string escape --style=url -- $$__cdenv_name)
+    end
+    for __cdenv_name in (functions --all --names)
+        string match -q -r '^__fish' -- $__cdenv_name; and continue
+        echo func (string escape --style=url -- $__cdenv_name) (functions -- $__cdenv_name | string collect | string escape --style=url)
+    end
+    # abbr --list and abbr --show print the abbreviations in the same order.
+    set -l __cdenv_names (abbr --list)
+    set -l __cdenv_lines (abbr --show)
+    for __cdenv_i in (seq (count $__cdenv_names))
+        echo abbr (string escape --style=url -- $__cdenv_names[$__cdenv_i]) (string escape --style=url -- $__cdenv_lines[$__cdenv_i])
+    end
+end
+
+function c:state
+    # Print the parts of the shell state that cdenv cannot restore, so that
+    # compare can detect changes to them.
+    echo "# cdenv state"
+    if set -q __cdenv_pwd
+        echo "pwd $__cdenv_pwd"
+    else
+        echo "pwd $PWD"
+    end
+    for job in (jobs -p)
+        echo "job $job"
+    end
+end
+
+function cdenv
+    switch "$argv[1]"
+        case update
+            if test "$CDENV_AUTORELOAD" -eq 1; or test "$PWD" != "$CDENV_LAST"
+                c:update
+                set -g CDENV_LAST $PWD
+            end
+            for cb in $CDENV_CALLBACK
+                $cb
+            end
+
+        case reload
+            c:update reload
+
+        case edit
+            set -l file $PWD/$CDENV_FILE
+            if contains -- "$argv[2]" -b --base
+                set file $CDENV_STACK[-1]
+            end
+
+            # unload
+            test "$CDENV_AUTORELOAD" -ne 1; and c:store read $file >/dev/null; and c:unsource $file
+            # edit
+            if set -q EDITOR
+                $EDITOR $file
+            else
+                vi $file
+            end
+            # reload
+            test "$CDENV_AUTORELOAD" -ne 1; and test -e $file; and c:source $file
+
+        case status
+            $CDENV_EXEC status --store="$CDENV_CACHE/$fish_pid" $argv[2..-1] -- $CDENV_STACK
+
+        case blame
+            c:snapshot | $CDENV_EXEC blame --store="$CDENV_CACHE/$fish_pid" -- $CDENV_STACK
+
+        case why
+            if test -z "$argv[2]"
+                c.err "usage: cdenv why <name>"
+                return 2
+            end
+            c:snapshot | $CDENV_EXEC why --store="$CDENV_CACHE/$fish_pid" $argv[2] -- $CDENV_STACK
+
+        case plan
+            set -l plan_args
+            set -l stack $CDENV_STACK
+            test "$CDENV_AUTORELOAD" -eq 1; and set -a plan_args --autoreload
+            set -l directory $PWD
+            for arg in $argv[2..-1]
+                switch $arg
+                    case --fresh
+                        set stack
+                    case '-*'
+                        set -a plan_args $arg
+                    case '*'
+                        set directory $arg
+                end
+            end
+            $CDENV_EXEC plan --global=$CDENV_GLOBAL --path="$CDENV_PATH" --file=$CDENV_FILE --tag=$CDENV_TAG $plan_args -- $directory $stack
+
+        case drift
+            c:snapshot | $CDENV_EXEC drift --store="$CDENV_CACHE/$fish_pid" -- $CDENV_STACK
+
+        case gc
+            $CDENV_EXEC gc --cache="$CDENV_CACHE"
+
+        case log
+            $CDENV_EXEC log --log="$CDENV_LOG" $argv[2..-1]
+
+        case version
+            $CDENV_EXEC version
+
+        case help ''
+            echo >&2 "usage: cdenv <command> [<argument> ...]
+
+cdenv will check for a file called $CDENV_FILE every time you cd into a
+directory. This file will be sourced in the current environment. The changes
+to the environment are cumulative, i.e. the deeper you go in the directory
+tree each new $CDENV_FILE's changes are put on top of the others. Once you go
+back up in the tree the changes are undone one by one.
+
+Settings are stored in ~/$CDENV_RCFILE and are the same as for bash, see
+'cdenv help' in bash. CDENV_FILE defaults to .cdenv.fish and CDENV_PATH is
+empty, because the libraries that come with cdenv are written for bash. Only
+global variables are tracked, universal variables are left alone. A plain
+'set' in $CDENV_FILE sets a global variable, as if the file was sourced at
+the top level.
+
+commands:
+    help        This help message.
+    reload      Unload and reload the complete cdenv environment and all
+                $CDENV_FILE in the current directory hierarchy.
+    edit [-b]   Load the $CDENV_FILE from the current working directory in the
+                EDITOR for editing and reload it after that. If -b/--base is
+                given, the $CDENV_FILE from the nearest base is opened.
+    status [-v|--verbose] [--json]
+                List the loaded files in the order they were loaded with the
+                number of variables, functions and abbreviations each of them
+                added, modified and removed.
+    blame       List every variable, function and abbreviation that has been
+                changed by a loaded file together with the file that changed
+                it last.
+    why <name>  Show which loaded files changed <name>, its value before and
+                after each of them and whether it has been changed since.
+    plan [--fresh] [--json] [<directory>]
+                Show which files would be unloaded and loaded when changing
+                to <directory> and why, without doing it.
+    drift       List the variables, functions and abbreviations that have
+                been changed by something other than the loaded files since
+                cdenv was initialized.
+    gc          Remove restore files left behind by shells that did not exit
+                cleanly.
+    log [--file <path>] [--name <name>] [--pid <pid>] [--since <time>] [--until <time>]
+                Show the audit log."
+
+        case '*'
+            echo "unknown cdenv command '$argv[1]'" >&2
+            return 2
+    end
+end
+
+function c:on_pwd --on-variable PWD
+    # Load the files right after a cd, but not while they are sourced.
+    set -q __cdenv_busy; and return
+    cdenv update
+end
+
+function c:on_prompt --on-event fish_prompt
+    # Catch the first prompt and changed files if CDENV_AUTORELOAD is on.
+    cdenv update
+end
+
+if string match -q -r '^[0-2]\.' -- $version
+    c.err "only fish >= 3.0 is supported!"
+else if set -q __cdenv_error[1]
+    c.err "$__cdenv_error, cdenv is not activated"
+    functions -e c:on_pwd c:on_prompt
+else
+    # Remember the environment before any cdenv file is loaded, see 'cdenv
+    # drift'.
+    c:snapshot | $CDENV_EXEC baseline --store="$CDENV_CACHE/$fish_pid"
+
+    c.debug "executable: $CDENV_EXEC"
+    c.debug "cache directory: "(c.translate "$CDENV_CACHE/$fish_pid")
+end
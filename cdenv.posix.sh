@@ -0,0 +1,342 @@
+#
+# cdenv - cdenv.posix.sh
+#
+# Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+#
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU General Public License as published by
+# the Free Software Foundation, either version 3 of the License, or
+# (at your option) any later version.
+#
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU General Public License for more details.
+#
+# You should have received a copy of the GNU General Public License
+# along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+# A reduced cdenv for POSIX shells like dash or busybox sh. Only variables
+# are tracked, see src/sh.rs. There is no prompt hook, so cd is wrapped
+# instead.
+#
+# A POSIX shell has no local variables, so everything in here keeps its state
+# in global variables starting with __cdenv_, which cdenv ignores. Function
+# names may only contain letters, digits and underscores.
+
+CDENV_RCFILE=.cdenvrc.posix.sh
+
+# Defaults. A sourced script cannot find out where it is, so the executable is
+# looked up in PATH. This happens before the cdenv function is defined below.
+CDENV_VERBOSE=0
+CDENV_GLOBAL=1
+CDENV_FILE=.cdenv.sh
+CDENV_EXEC="$(command -v cdenv)"
+# The libraries in libs are written for bash.
+CDENV_PATH=
+# ~/.cache may be shared with other hosts.
+if [ -n "$XDG_RUNTIME_DIR" ]; then
+    CDENV_CACHE="$XDG_RUNTIME_DIR/cdenv"
+else
+    CDENV_CACHE="$HOME/.cache/cdenv/$(uname -n)"
+fi
+CDENV_LOG="${XDG_STATE_HOME:-$HOME/.local/state}/cdenv/log"
+CDENV_CALLBACK=
+CDENV_STACK=
+CDENV_AUTORELOAD=0
+CDENV_TAG=0
+CDENV_REDACT=
+CDENV_SHADOW_LEVEL=1
+
+# Load the settings file selectively replacing the defaults from above.
+[ -e "$HOME/$CDENV_RCFILE" ] && . "$HOME/$CDENV_RCFILE"
+
+# Create a directory for the restore files which will be removed in the EXIT
+# trap handler. The restore files contain the values of variables, so nobody
+# else must be able to read them. Directories left behind by shells that did
+# not exit cleanly are removed along the way. If the directory belongs to a
+# shell on another host that shares the cache directory, cdenv is not
+# activated.
+__cdenv_error="$("$CDENV_EXEC" gc --cache="${CDENV_CACHE:?}" --pid=$$ 2>&1)"
+
+cdenv_exit() {
+    rm -r "${CDENV_CACHE:?}/$$"
+}
+[ -z "$__cdenv_error" ] && trap cdenv_exit EXIT
+
+c_err() {
+    # Print an error message to stderr.
+    echo "ERROR: $*" >&2
+}
+
+c_warn() {
+    # Print a warning message to stderr.
+    echo "WARNING: $*" >&2
+}
+
+c_leak() {
+    # Report a change to the shell state that cannot be undone.
+    echo "LEAK: $*" >&2
+}
+
+c_msg() {
+    # Print a message to stderr.
+    [ "$CDENV_VERBOSE" -ge 1 ] && echo "$*" >&2
+    return 0
+}
+
+c_debug() {
+    # Print a debug message to stderr.
+    [ "$CDENV_VERBOSE" -ge 2 ] && echo "cdenv: $*" >&2
+    return 0
+}
+
+cdenv_store() {
+    # Access the restore files in $CDENV_CACHE/<pid>, see 'cdenv store'.
+    "$CDENV_EXEC" store --dir="$CDENV_CACHE/$$" "$@"
+}
+
+cdenv_with_stack() {
+    # Run a command with the files from CDENV_STACK, one per line, as
+    # additional arguments.
+    __cdenv_ifs="$IFS"
+    case $- in
+        *f*) __cdenv_noglob=1 ;;
+        *) __cdenv_noglob= ;;
+    esac
+    IFS='
+'
+    set -f
+    # shellcheck disable=SC2086
+    "$@" $CDENV_STACK
+    __cdenv_status=$?
+    [ -z "$__cdenv_noglob" ] && set +f
+    IFS="$__cdenv_ifs"
+    return $__cdenv_status
+}
+
+cdenv_safe_source() {
+    # Source a file in the context of a specific directory.
+    __cdenv_oldpwd="$OLDPWD"
+    __cdenv_savedir="$PWD"
+
+    command cd "$(dirname "$__cdenv_file")" || return 1
+    # Check the script for errors before sourcing.
+    if sh -n "$__cdenv_file"; then
+        . "$__cdenv_file"
+    fi
+    # Remember where the file left us, see cdenv_state.
+    __cdenv_pwd="$PWD"
+    command cd "$__cdenv_savedir" || return 1
+    OLDPWD="$__cdenv_oldpwd"
+}
+
+cdenv_update() {
+    __cdenv_autoreload=
+    [ "$CDENV_AUTORELOAD" -eq 1 ] && __cdenv_autoreload=--autoreload
+    # shellcheck disable=SC2086
+    __cdenv_code="$(cdenv_with_stack "$CDENV_EXEC" list --shell=sh --global="$CDENV_GLOBAL" --path="$CDENV_PATH" --file="$CDENV_FILE" --tag="$CDENV_TAG" --log="$CDENV_LOG" --pid=$$ --cache="$CDENV_CACHE" ${1:+--reload} $__cdenv_autoreload "$PWD")"
+    eval "$__cdenv_code"
+
+    while IFS= read -r __cdenv_path; do
+        [ -n "$__cdenv_path" ] && c_msg "$__cdenv_path was removed"
+    done <<EOF
+$__cdenv_removed
+EOF
+    while IFS= read -r __cdenv_path; do
+        [ -n "$__cdenv_path" ] && c_msg "$__cdenv_path was changed"
+    done <<EOF
+$__cdenv_changed
+EOF
+
+    # First undo the changes made to the environment.
+    while IFS= read -r __cdenv_path; do
+        [ -n "$__cdenv_path" ] && cdenv_unsource "$__cdenv_path"
+    done <<EOF
+$__cdenv_unload
+EOF
+
+    if [ "$1" = reload ] && [ -e "$HOME/$CDENV_RCFILE" ]; then
+        # Reload the settings file.
+        c_msg "reloading ~/$CDENV_RCFILE"
+        if sh -n "$HOME/$CDENV_RCFILE"; then
+            . "$HOME/$CDENV_RCFILE"
+        fi
+    fi
+
+    # Source the needed cdenv files.
+    while IFS= read -r __cdenv_path; do
+        [ -n "$__cdenv_path" ] && cdenv_source "$__cdenv_path"
+    done <<EOF
+$__cdenv_load
+EOF
+
+    unset __cdenv_removed __cdenv_changed __cdenv_unload __cdenv_load __cdenv_code
+}
+
+cdenv_unsource() {
+    # Undo the changes from a single cdenv file.
+    c_msg "unsource $1"
+    __cdenv_code="$(cdenv_store read "$1")"
+    eval "$__cdenv_code"
+    cdenv_store remove "$1"
+}
+
+cdenv_source() {
+    # Source a single cdenv file and keep track of the changes to the
+    # environment. Everything that the functions below set must be set
+    # before the first snapshot, so that it does not show up as a change.
+    __cdenv_file="$1"
+    __cdenv_before="$(mktemp "$CDENV_CACHE/$$/snapshot.XXXXXX")"
+    __cdenv_after="$(mktemp "$CDENV_CACHE/$$/snapshot.XXXXXX")"
+    __cdenv_oldpwd="$OLDPWD"
+    __cdenv_savedir="$PWD"
+    __cdenv_code=
+
+    # Save a snapshot of the environment. There is no process substitution,
+    # so it goes to a file next to the restore files.
+    { cdenv_snapshot; cdenv_state; } >"$__cdenv_before"
+
+    # Source the cdenv file.
+    c_msg "source $__cdenv_file"
+    cdenv_safe_source
+
+    # Save another snapshot of the environment and compare both. Create a
+    # restore file that can be used to undo all changes to the environment when
+    # changing to another directory.
+    { cdenv_snapshot; cdenv_state; } >"$__cdenv_after"
+    __cdenv_code="$(cdenv_with_stack "$CDENV_EXEC" compare --log="$CDENV_LOG" --pid=$$ --file="$__cdenv_file" --redact="$CDENV_REDACT" --store="$CDENV_CACHE/$$" --shadow="$CDENV_SHADOW_LEVEL" "$__cdenv_before" <"$__cdenv_after")"
+    rm -f "$__cdenv_before" "$__cdenv_after"
+    unset __cdenv_pwd
+    eval "$__cdenv_code"
+}
+
+cdenv_snapshot() {
+    # Print the exported variables and all variables.
+    echo "# cdenv sh"
+    echo "# cdenv exports"
+    export -p
+    echo "# cdenv variables"
+    set
+}
+
+cdenv_state() {
+    # Print the parts of the shell state that cdenv cannot restore, so that
+    # compare can detect changes to them.
+    echo "# cdenv state"
+    echo "pwd ${__cdenv_pwd:-$PWD}"
+    jobs -p | sed 's/^/job /'
+}
+
+cdenv() {
+    case "$1" in
+        update)
+            if [ "$CDENV_AUTORELOAD" -eq 1 ] || [ "$PWD" != "$CDENV_LAST" ]; then
+                cdenv_update
+                CDENV_LAST="$PWD"
+            fi
+            for __cdenv_cb in $CDENV_CALLBACK; do
+                $__cdenv_cb
+            done
+            ;;
+
+        reload)
+            cdenv_update reload
+            ;;
+
+        status)
+            shift
+            cdenv_with_stack "$CDENV_EXEC" status --store="$CDENV_CACHE/$$" "$@" --
+            ;;
+
+        blame)
+            cdenv_snapshot | cdenv_with_stack "$CDENV_EXEC" blame --store="$CDENV_CACHE/$$" --
+            ;;
+
+        why)
+            if [ -z "$2" ]; then
+                c_err "usage: cdenv why <name>"
+                return 2
+            fi
+            cdenv_snapshot | cdenv_with_stack "$CDENV_EXEC" why --store="$CDENV_CACHE/$$" "$2" --
+            ;;
+
+        plan)
+            shift
+            cdenv_with_stack "$CDENV_EXEC" plan --global="$CDENV_GLOBAL" --path="$CDENV_PATH" --file="$CDENV_FILE" --tag="$CDENV_TAG" -- "${1:-$PWD}"
+            ;;
+
+        drift)
+            cdenv_snapshot | cdenv_with_stack "$CDENV_EXEC" drift --store="$CDENV_CACHE/$$" --
+            ;;
+
+        gc)
+            "$CDENV_EXEC" gc --cache="$CDENV_CACHE"
+            ;;
+
+        log)
+            shift
+            "$CDENV_EXEC" log --log="$CDENV_LOG" "$@"
+            ;;
+
+        version)
+            "$CDENV_EXEC" version
+            ;;
+
+        help|"")
+            cat >&2 <<EOF
+usage: cdenv <command> [<argument> ...]
+
+cdenv will check for a file called $CDENV_FILE every time you cd into a
+directory and source it. Once you go back up in the tree the changes are
+undone. This is the reduced version for POSIX shells, it only keeps track of
+variables, not of functions, aliases or options.
+
+Settings are stored in ~/$CDENV_RCFILE and are the same as for bash, see
+'cdenv help' in bash. CDENV_EXEC defaults to cdenv from PATH, CDENV_PATH is
+empty and CDENV_CALLBACK is a list of commands separated by spaces.
+
+commands:
+    help        This help message.
+    reload      Unload and reload all $CDENV_FILE in the current directory
+                hierarchy and the settings file.
+    status [-v|--verbose] [--json]
+                List the loaded files with the number of variables each of
+                them added, modified and removed.
+    blame       List every variable that has been changed by a loaded file
+                together with the file that changed it last.
+    why <name>  Show which loaded files changed <name>.
+    plan [<directory>]
+                Show which files would be unloaded and loaded when changing
+                to <directory> and why, without doing it.
+    drift       List the variables that have been changed by something other
+                than the loaded files since cdenv was initialized.
+    gc          Remove restore files left behind by shells that did not exit
+                cleanly.
+    log [--file <path>] [--name <name>] [--pid <pid>] [--since <time>] [--until <time>]
+                Show the audit log.
+EOF
+            ;;
+
+        *)
+            echo "unknown cdenv command '$1'" >&2
+            return 2
+            ;;
+    esac
+}
+
+if [ -n "$__cdenv_error" ]; then
+    c_err "$__cdenv_error, cdenv is not activated"
+else
+    cd() {
+        # There is no prompt hook in a POSIX shell, so the files are loaded
+        # right after changing the directory.
+        command cd "$@" && cdenv update
+    }
+
+    # Remember the environment before any cdenv file is loaded, see 'cdenv
+    # drift'.
+    cdenv_snapshot | "$CDENV_EXEC" baseline --store="$CDENV_CACHE/$$"
+    cdenv update
+fi
+unset __cdenv_error
@@ -26,14 +26,26 @@ CDENV_FILE=.cdenv.sh
 CDENV_SH="$(realpath "${BASH_SOURCE[0]}")"
 CDENV_EXEC="$(dirname "$CDENV_SH")/cdenv"
 CDENV_PATH="$(dirname "$CDENV_SH")/libs"
-CDENV_CACHE="$HOME/.cache/cdenv"
+# ~/.cache may be shared with other hosts.
+if [ -n "$XDG_RUNTIME_DIR" ]; then
+    CDENV_CACHE="$XDG_RUNTIME_DIR/cdenv"
+else
+    CDENV_CACHE="$HOME/.cache/cdenv/$HOSTNAME"
+fi
+CDENV_LOG="${XDG_STATE_HOME:-$HOME/.local/state}/cdenv/log"
 declare -a CDENV_CALLBACK=()
 declare -a CDENV_STACK=()
 CDENV_AUTORELOAD=0
 CDENV_TAG=0
+CDENV_REDACT=
+CDENV_SHADOW_LEVEL=1
+CDENV_SERVE=0
+CDENV_BUILTIN=
+declare -A CDENV_REDACTED=()
 
 CDENV_COLOR=1
 CDENV_COLOR_ERR=$(tput setaf 1)
+CDENV_COLOR_WARN=$(tput setaf 3)
 CDENV_COLOR_MSG=$(tput setaf 4)
 CDENV_COLOR_DEBUG=$(tput setaf 8)
 CDENV_COLOR_RESET=$(tput setaf sgr0)
@@ -42,8 +54,12 @@ CDENV_COLOR_RESET=$(tput setaf sgr0)
 [[ -e $HOME/$CDENV_RCFILE ]] && source "$HOME/$CDENV_RCFILE"
 
 # Create a directory for the restore files which will be removed in the EXIT
-# trap handler.
-mkdir -p "${CDENV_CACHE:?}/$$"
+# trap handler. The restore files contain the values of variables, so nobody
+# else must be able to read them. Directories left behind by shells that did
+# not exit cleanly are removed along the way. If the directory belongs to a
+# shell on another host that shares the cache directory, cdenv is not
+# activated.
+__cdenv_error="$($CDENV_EXEC gc --cache="${CDENV_CACHE:?}" --pid=$$ 2>&1)"
 
 c:exit() {
     rm -r "${CDENV_CACHE:?}/$$"
@@ -52,11 +68,12 @@ c:exit() {
         $cb
     done
 }
-trap c:exit EXIT
+[[ -z $__cdenv_error ]] && trap c:exit EXIT
 
 # Switch off all colors if requested.
 if [[ ! -t 1 || $CDENV_COLOR -ne 1 ]]; then
     unset CDENV_COLOR_ERR
+    unset CDENV_COLOR_WARN
     unset CDENV_COLOR_MSG
     unset CDENV_COLOR_DEBUG
     unset CDENV_COLOR_RESET
@@ -67,6 +84,16 @@ c.err() {
     echo "${CDENV_COLOR_ERR}ERROR: $*${CDENV_COLOR_RESET}" >&2
 }
 
+c.warn() {
+    # Print a warning message to stderr.
+    echo "${CDENV_COLOR_WARN}WARNING: $*${CDENV_COLOR_RESET}" >&2
+}
+
+c:leak() {
+    # Report a change to the shell state that cannot be undone.
+    echo "${CDENV_COLOR_WARN}LEAK: $*${CDENV_COLOR_RESET}" >&2
+}
+
 c.msg() {
     # Print a message to stderr.
     [[ $CDENV_VERBOSE -ge 1 ]] && echo "${CDENV_COLOR_MSG}$*${CDENV_COLOR_RESET}" >&2
@@ -91,9 +118,64 @@ c.translate() {
     fi
 }
 
-c:restore_path() {
-    # Save restore files in ~/.cache/cdenv/<pid>/<path>.sh.
-    echo "$CDENV_CACHE/$$/${1//\//%2F}"
+c:store() {
+    # Access the restore files in $CDENV_CACHE/<pid>, see 'cdenv store'.
+    $CDENV_EXEC store --dir="$CDENV_CACHE/$$" "$@"
+}
+
+c:serve() {
+    # Start cdenv serve as a coprocess, unless it is already running. The
+    # snapshots are sent ahead under names that end with a random token,
+    # because their last line must not occur in the snapshot itself.
+    c:serving && return
+    __cdenv_token="$RANDOM$RANDOM$RANDOM"
+    coproc CDENV_SERVER { exec $CDENV_EXEC serve; }
+    c.debug "cdenv serve is running as pid $CDENV_SERVER_PID"
+}
+
+c:builtin() {
+    # Load cdenv_core from CDENV_BUILTIN, unless it is already there. If that
+    # does not work, CDENV_BUILTIN is cleared and the executable is used.
+    enable cdenv_core 2>/dev/null && return
+    if ! enable -f "$CDENV_BUILTIN" cdenv_core; then
+        c.err "unable to load $(c.translate "$CDENV_BUILTIN")"
+        CDENV_BUILTIN=
+    fi
+}
+
+c:serving() {
+    # Return 0 if list and compare go to the coprocess.
+    [[ $CDENV_SERVE -eq 1 && -n $CDENV_SERVER_PID ]] && kill -0 "$CDENV_SERVER_PID" 2>/dev/null
+}
+
+c:request() {
+    # Send a request to the coprocess and put the shell code from the
+    # response in __cdenv_code, see src/serve.rs. Each argument goes on a line
+    # of its own, so backslashes and newlines are escaped.
+    local count arg
+    local -a args=() lines=()
+    for arg in "$@"; do
+        arg="${arg//\\/\\\\}"
+        args+=("${arg//$'\n'/\\n}")
+    done
+    printf '%s\n' "$#" "${args[@]}" >&"${CDENV_SERVER[1]}" || return 1
+    read -r -u "${CDENV_SERVER[0]}" count || return 1
+    # mapfile reads everything with a count of 0.
+    [[ $count -gt 0 ]] && mapfile -n "$count" -u "${CDENV_SERVER[0]}" lines
+    printf -v __cdenv_code '%s' "${lines[@]}"
+}
+
+c:send() {
+    # Send the snapshot from before or after sourcing a file to the coprocess,
+    # without a subshell. There must be no local variables in here, they would
+    # be part of the snapshot.
+    {
+        printf '2\nsnapshot\n%s\n' "$1.$__cdenv_token"
+        c:snapshot
+        [[ $1 = after ]] && c:locations
+        c:state
+        echo "$1.$__cdenv_token"
+    } >&"${CDENV_SERVER[1]}"
 }
 
 c:safe_source() {
@@ -108,6 +190,8 @@ c:safe_source() {
     if $BASH -n "$path"; then
         source "$path"
     fi
+    # Remember where the file left us, see c:state.
+    __cdenv_pwd="$PWD"
     builtin cd "$savedir" || return 1
     OLDPWD="$oldpwd"
 }
@@ -120,8 +204,20 @@ c:update() {
     [[ $1 = reload ]] && args+=(--reload)
     [[ $CDENV_AUTORELOAD -eq 1 ]] && args+=(--autoreload)
 
+    local __cdenv_code
+    # The executable also takes over if the coprocess has gone away.
     # shellcheck disable=SC2086
-    eval "$($CDENV_EXEC list --global=$CDENV_GLOBAL --path="$CDENV_PATH" --file=$CDENV_FILE --tag=$CDENV_TAG ${args[*]} "$PWD" "${CDENV_STACK[@]}")"
+    if [[ -n $CDENV_BUILTIN ]]; then
+        # The builtin sets the arrays itself, they must be declared here to
+        # become local.
+        local -a unload=() load=()
+        cdenv_core list "${args[@]}"
+    elif c:serving && c:request list --global=$CDENV_GLOBAL --path="$CDENV_PATH" --file=$CDENV_FILE --tag=$CDENV_TAG --log="$CDENV_LOG" --pid=$$ --cache="$CDENV_CACHE" ${args[*]} "$PWD" "${CDENV_STACK[@]}"; then
+        :
+    else
+        __cdenv_code="$($CDENV_EXEC list --global=$CDENV_GLOBAL --path="$CDENV_PATH" --file=$CDENV_FILE --tag=$CDENV_TAG --log="$CDENV_LOG" --pid=$$ --cache="$CDENV_CACHE" ${args[*]} "$PWD" "${CDENV_STACK[@]}")"
+    fi
+    eval "$__cdenv_code"
 
     for path in "${removed[@]}"; do
         c.msg "$(c.translate "$path") was removed"
@@ -157,12 +253,9 @@ c:update() {
 c:unsource() {
     # Undo the changes from a single cdenv file.
     local path="$1"
-    local restore="$(c:restore_path "$path")"
     c.msg "unsource $(c.translate "$path")"
-    if [[ -e $restore ]]; then
-        source "$restore"
-        rm "$restore"
-    fi
+    source <(c:store read "$path")
+    c:store remove "$path"
 }
 
 c:source() {
@@ -170,11 +263,25 @@ c:source() {
     # environment. Try to avoid collisions with names from the sources.
     local __path="$1"
     local __directory="$(dirname "$__path")"
-    local __restore="$(c:restore_path "$__path")"
 
-    # Save a snapshot of the environment.
-    local __tmp="$CDENV_CACHE/$$.tmp"
-    { declare -p; declare -f; alias; } > "$__tmp"
+    # Save a snapshot of the environment. The builtin keeps it in the shell
+    # process, otherwise it goes to cdenv serve right away, or to a private
+    # file in the restore directory that is removed as soon as it is open. A
+    # pipe would block the shell if the file waits for its jobs while the
+    # snapshot does not fit into the pipe buffer.
+    local __cdenv_fd __cdenv_pwd __cdenv_code __cdenv_snapshot __cdenv_via=exec
+    if [[ -n $CDENV_BUILTIN ]]; then
+        __cdenv_via=builtin
+        cdenv_core snapshot before 'c:snapshot; c:state'
+    elif c:serving; then
+        __cdenv_via=serve
+        c:send before
+    else
+        __cdenv_snapshot="$(mktemp "$CDENV_CACHE/$$/snapshot.XXXXXX")" || return 1
+        (c:snapshot; c:state) >"$__cdenv_snapshot"
+        exec {__cdenv_fd}<"$__cdenv_snapshot"
+        rm -f "$__cdenv_snapshot"
+    fi
 
     # Source the cdenv file.
     c.msg "source $(c.translate "$__path")"
@@ -183,39 +290,61 @@ c:source() {
     # Save another snapshot of the environment and compare both. Create a
     # restore file that can be used to undo all changes to the environment when
     # changing to another directory.
-    eval "$({ declare -p; declare -f; alias; } | $CDENV_EXEC compare "$__tmp" "$__restore")"
-    rm "$__tmp"
+    # The snapshot is passed in a process substitution or sent directly instead
+    # of a pipe, because a pipe would not see the jobs of the shell. compare
+    # reads it while it is written.
+    if [[ $__cdenv_via = builtin ]]; then
+        cdenv_core snapshot after 'c:snapshot; c:locations; c:state'
+        cdenv_core compare "$__path" before after
+    elif [[ $__cdenv_via = serve ]]; then
+        c:send after
+        if ! c:request compare --log="$CDENV_LOG" --pid=$$ --file="$__path" --redact="$CDENV_REDACT" --store="$CDENV_CACHE/$$" --shadow="$CDENV_SHADOW_LEVEL" --input="after.$__cdenv_token" "before.$__cdenv_token" "${CDENV_STACK[@]}"; then
+            c.err "cdenv serve has gone away, the changes from $(c.translate "$__path") cannot be undone"
+        fi
+    else
+        __cdenv_code="$($CDENV_EXEC compare --log="$CDENV_LOG" --pid=$$ --file="$__path" --redact="$CDENV_REDACT" --store="$CDENV_CACHE/$$" --shadow="$CDENV_SHADOW_LEVEL" "/dev/fd/$__cdenv_fd" "${CDENV_STACK[@]}" < <(c:snapshot; c:locations; c:state))"
+        exec {__cdenv_fd}<&-
+    fi
+    eval "$__cdenv_code"
 }
 
-c:find_file() {
-    # Go through the stack in reverse looking for a variable, function or alias
-    # definition.
-    local a="$1"
-    local i
-    for ((i = ${#CDENV_STACK[@]}-1; i >= 0; i--)); do
-        local f="${CDENV_STACK[$i]}"
-        local p="$(c:restore_path "$f")"
-        if grep -q "^# $a\$" "$p"; then
-            echo "$f"
-            return
-        fi
+c:snapshot() {
+    # Print the variables, functions and aliases. The output format depends on
+    # the bash version and on posix mode, so we tell the parser about them.
+    if [[ -o posix ]]; then
+        echo "# cdenv bash ${BASH_VERSINFO[0]}.${BASH_VERSINFO[1]} posix"
+    else
+        echo "# cdenv bash ${BASH_VERSINFO[0]}.${BASH_VERSINFO[1]}"
+    fi
+    declare -p
+    declare -f
+    alias
+}
+
+c:state() {
+    # Print the parts of the shell state that cdenv cannot restore, so that
+    # compare can detect changes to them.
+    echo "# cdenv state"
+    echo "pwd ${__cdenv_pwd:-$PWD}"
+    local job
+    for job in $(jobs -pr); do
+        echo "job $job"
     done
+    echo "history $HISTCMD"
 }
 
-c:line_number() {
-    # Return the line number of a specific variable / function / alias
-    # definition in a file.
-    local n="$1"
-    local f="$2"
-    local p
-
-    for p in "${n}=" \
-             "${n}[[:space:]]*([[:space:]]*)" \
-             "alias[[:space:]]\+${n}="; do
-        awk "/^[^#]/ && /$p/ { print \"+\" NR; ex = 1; exit 0; } \
-             END { if (!ex) exit 1; }" \
-            "$f" && break
+c:locations() {
+    # Print the locations of all function definitions. This may run in the
+    # interactive shell itself, so leave extdebug the way we found it.
+    local name extdebug
+    extdebug="$(shopt -p extdebug)"
+    shopt -s extdebug
+    echo "# cdenv locations"
+    # In posix mode, declare rejects names that are not identifiers.
+    compgen -A function | while read -r name; do
+        declare -F "$name" 2>/dev/null
     done
+    eval "$extdebug"
 }
 
 cdenv() {
@@ -236,33 +365,115 @@ cdenv() {
             ;;
 
         edit)
-            local path lineno
+            local path target lineno location
             case "$2" in
                 -b|--base)
                     path="${CDENV_STACK[${#CDENV_STACK[@]}-1]}"
+                    target="$path"
                     ;;
                 "")
                     path="$PWD/$CDENV_FILE"
+                    target="$path"
                     ;;
                 *)
-                    path="$(c:find_file "$2")"
-                    if [[ -z "$path" ]]; then
-                        c.err "no such variable / function / alias: $2"
-                        return 1
-                    fi
-                    lineno="$(c:line_number "$2" "$path")"
+                    location="$($CDENV_EXEC locate --store="$CDENV_CACHE/$$" "$2" -- "${CDENV_STACK[@]}")" || return 1
+                    path="$($CDENV_EXEC locate --store="$CDENV_CACHE/$$" --layer "$2" -- "${CDENV_STACK[@]}")"
+                    target="${location%:*}"
+                    lineno="+${location##*:}"
                     ;;
             esac
 
             # unload
-            [[ $CDENV_AUTORELOAD -ne 1 && -e "$(c:restore_path "$path")" ]] && c:unsource "$path"
+            [[ $CDENV_AUTORELOAD -ne 1 ]] && c:store read "$path" >/dev/null && c:unsource "$path"
             # edit
             # shellcheck disable=SC2086
-            ${EDITOR:-vi} $lineno "$path"
+            ${EDITOR:-vi} $lineno "$target"
             # reload
             [[ $CDENV_AUTORELOAD -ne 1 && -e "$path" ]] && c:source "$path"
             ;;
 
+        status)
+            shift
+            $CDENV_EXEC status --store="$CDENV_CACHE/$$" "$@" -- "${CDENV_STACK[@]}"
+            ;;
+
+        blame)
+            c:snapshot | $CDENV_EXEC blame --store="$CDENV_CACHE/$$" -- "${CDENV_STACK[@]}"
+            ;;
+
+        why)
+            if [[ -z $2 ]]; then
+                c.err "usage: cdenv why <name>"
+                return 2
+            fi
+            c:snapshot | $CDENV_EXEC why --store="$CDENV_CACHE/$$" "$2" -- "${CDENV_STACK[@]}"
+            ;;
+
+        plan)
+            shift
+            local -a plan_args=() stack=("${CDENV_STACK[@]}")
+            [[ $CDENV_AUTORELOAD -eq 1 ]] && plan_args+=(--autoreload)
+            while [[ $1 == -* ]]; do
+                case "$1" in
+                    --fresh) stack=() ;;
+                    *) plan_args+=("$1") ;;
+                esac
+                shift
+            done
+            $CDENV_EXEC plan --global=$CDENV_GLOBAL --path="$CDENV_PATH" --file=$CDENV_FILE --tag=$CDENV_TAG "${plan_args[@]}" -- "${1:-$PWD}" "${stack[@]}"
+            ;;
+
+        drift)
+            c:snapshot | $CDENV_EXEC drift --store="$CDENV_CACHE/$$" -- "${CDENV_STACK[@]}"
+            ;;
+
+        gc)
+            $CDENV_EXEC gc --cache="$CDENV_CACHE"
+            ;;
+
+        locate)
+            if [[ -z $2 ]]; then
+                c.err "usage: cdenv locate <name>"
+                return 2
+            fi
+            $CDENV_EXEC locate --store="$CDENV_CACHE/$$" "$2" -- "${CDENV_STACK[@]}"
+            ;;
+
+        lint)
+            shift
+            local -a lint_args=()
+            while [[ $1 == -* ]]; do
+                lint_args+=("$1")
+                shift
+            done
+            [[ $# -eq 0 ]] && set -- "$PWD/$CDENV_FILE"
+            $CDENV_EXEC lint --path="$CDENV_PATH" "${lint_args[@]}" -- "$@"
+            ;;
+
+        verify)
+            $CDENV_EXEC verify --sh="$CDENV_SH" "${2:-$PWD}"
+            ;;
+
+        export)
+            shift
+            local -a export_args=()
+            while [[ $1 == -* ]]; do
+                export_args+=("$1")
+                shift
+            done
+            $CDENV_EXEC export --sh="$CDENV_SH" "${export_args[@]}" -- "${1:-$PWD}"
+            ;;
+
+        exec)
+            shift
+            $CDENV_EXEC exec --sh="$CDENV_SH" "$@"
+            ;;
+
+        log)
+            shift
+            $CDENV_EXEC log --log="$CDENV_LOG" "$@"
+            ;;
+
         version)
             $CDENV_EXEC version
             ;;
@@ -316,6 +527,38 @@ CDENV_COLOR={0|1}
     If set to 1, use colored output for error messages and debug messages
     (if CDENV_VERBOSE > 0), default is 1.
 
+CDENV_REDACT={regex}
+    (current: $CDENV_REDACT)
+    The previous values of variables whose name or unquoted value matches
+    this regular expression are not written to the restore files but are kept
+    in memory, e.g. '(TOKEN|SECRET|PASSWORD|API_?KEY)' or '^sk-'. The name and
+    the value are matched separately. Default is empty.
+
+CDENV_SHADOW_LEVEL={0|1|2|3}
+    (current: $CDENV_SHADOW_LEVEL)
+    Warn if a $CDENV_FILE redefines or removes a function or alias from a
+    file that was loaded before, if CDENV_VERBOSE is at least at this level.
+    Use 0 to always see the warnings or 3 to never see them, default is 1.
+
+CDENV_LOG={filename}
+    (current: $CDENV_LOG)
+    The audit log that records every file that is loaded or unloaded and every
+    name that is changed by it. Set it to an empty value to disable the log.
+
+CDENV_SERVE={0|1}
+    (current: $CDENV_SERVE)
+    If set to 1, the cdenv executable runs as a coprocess for the lifetime of
+    the shell, so that it is not started again on every prompt and for every
+    file that is loaded. This helps on slow file systems, especially with
+    CDENV_AUTORELOAD=1. The coprocess is listed by 'jobs'. Default is 0.
+
+CDENV_BUILTIN={filename}
+    (current: $CDENV_BUILTIN)
+    The cdenv_core.so loadable builtin. If set, the files to load and unload
+    are found and the snapshots are compared inside the shell, without
+    starting the executable on every prompt and for every file that is
+    loaded. Unloading files still uses the executable. Default is empty.
+
 
 commands:
     help        This help message.
@@ -327,6 +570,54 @@ commands:
                 -b/--base is given, the $CDENV_FILE from the nearest base is
                 opened. If a <name> is given, open the script file where this
                 name has most recently been defined.
+    status [-v|--verbose] [--json]
+                List the loaded files in the order they were loaded with the
+                number of variables, functions, aliases and options each of
+                them added, modified and removed. Use -v/--verbose to see the
+                names and --json to get the output in JSON format. Files
+                that leaked changes that cannot be undone are flagged.
+    blame       List every variable, function, alias and option that has been
+                changed by a loaded file together with the file that changed
+                it last.
+    why <name>  Show which loaded files changed <name>, its value before and
+                after each of them and whether it has been changed since.
+    plan [--fresh] [--json] [<directory>]
+                Show which files would be unloaded and loaded when changing
+                to <directory> and why, without doing it. With --fresh, show
+                what would be loaded in a new shell.
+    drift       List the variables, functions, aliases and options that have
+                been changed by something other than the loaded files since
+                cdenv was initialized.
+    locate <name>
+                Print the file and line number where <name> has most recently
+                been defined.
+    lint [--json] [<file> ...]
+                Check the $CDENV_FILE in the current working directory or the
+                given files for commands that cdenv cannot undo or that break
+                the interactive shell, e.g. exit, cd or set -e. Use --json to
+                get the output in JSON format.
+    verify [<directory>]
+                Load all $CDENV_FILE files for the current working directory
+                or <directory> in a clean bash, unload them again and report
+                everything that was not restored.
+    exec <directory> -- <command> [<argument> ...]
+                Run a command in the environment that the $CDENV_FILE files
+                for <directory> create, without an interactive shell. The
+                command runs in the current working directory. This is also
+                available as 'cdenv exec' from the executable itself, e.g. for
+                scripts, cron jobs or make.
+    export [--format=<format>] [<directory>]
+                Print the exported variables that the $CDENV_FILE files for
+                the current working directory or <directory> set, for use in
+                other programs. <format> is one of dotenv (the default), json,
+                systemd, docker or export.
+    gc          Remove restore files left behind by shells that did not exit
+                cleanly.
+    log [--file <path>] [--name <name>] [--pid <pid>] [--since <time>] [--until <time>]
+                Show the audit log. <path> is either a $CDENV_FILE or a
+                directory, <time> is either seconds since the epoch, a
+                duration like 30m, 2h or 1d, or an UTC date like
+                2021-11-07T14:32.
 EOF
             ;;
 
@@ -387,6 +678,13 @@ else
     if [[ ${BASH_SOURCE[0]} != "$0" ]]; then
         case "$1" in
             "")
+                if [[ -n $__cdenv_error ]]; then
+                    c.err "$__cdenv_error, cdenv is not activated"
+                    unset __cdenv_error; unset -f c:install
+                    return 1
+                fi
+                unset __cdenv_error
+
                 if [[ ${PROMPT_COMMAND[*]} =~ "cdenv update" ]]; then
                     c.debug "cdenv is already installed"
                 else
@@ -397,6 +695,14 @@ else
                     PROMPT_COMMAND+=("cdenv update")
                 fi
 
+                # Remember the environment before any cdenv file is loaded,
+                # see 'cdenv drift'. c:install is removed below, the pipe
+                # runs in a subshell so we can leave it out here already.
+                { unset -f c:install; c:snapshot; } | $CDENV_EXEC baseline --store="$CDENV_CACHE/$$"
+
+                [[ $CDENV_SERVE -eq 1 ]] && c:serve
+                [[ -n $CDENV_BUILTIN ]] && c:builtin
+
                 c.debug "executable: $CDENV_EXEC"
                 c.debug "cache directory: $(c.translate "$CDENV_CACHE/$$")"
                 c.debug "autoreload is $(if [[ $CDENV_AUTORELOAD -eq 1 ]]; then echo on; else echo off; fi)"
@@ -16,16 +16,19 @@
 # along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 c.leave() {
+    # Put code in the restore file of the cdenv file that is currently being
+    # sourced by c:source.
     local funcname=${1:?}
-    local path="$(c.restore_path "$(pwd)")"
     if [[ $(type -t $funcname) != function ]]; then
         echo "no function named $funcname" >&2
         return 1
     fi
     c.debug "register leave function $funcname"
-    declare -f $funcname >> "$path"
-    echo $funcname >> "$path"
-    echo "unset -f $funcname" >> "$path"
+    {
+        declare -f $funcname
+        echo $funcname
+        echo "unset -f $funcname"
+    } | c:store write "${__path:?}"
     unset -f $funcname
 }
 
@@ -0,0 +1,240 @@
+// cdenv - audit.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The audit log is a plain text file with one record per line. The fields are separated by tabs:
+//
+//   <timestamp> <pid> <event> <file> <name>
+//
+// <timestamp> is in seconds since the epoch, <event> is one of load, unload, add, remove,
+// modify, set-on and set-off. <name> is empty for load and unload events and carries the same
+// suffix as the debug messages for all other events, i.e. "()" for functions and "*" for
+// aliases.
+pub struct Record {
+    pub event: String,
+    pub file: String,
+    pub name: String
+}
+
+impl Record {
+    pub fn new(event: &str, file: &str, name: &str) -> Record {
+        Record {
+            event: event.to_string(),
+            file: file.to_string(),
+            name: name.to_string()
+        }
+    }
+}
+
+pub struct Filter {
+    pub file: Option<String>,
+    pub name: Option<String>,
+    pub pid: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>
+}
+
+// Append a batch of records to the log file. All records are written with a single write call,
+// so that records from different shells do not get mixed up.
+pub fn append(logfile: &str, pid: &str, records: &[Record]) {
+    if records.is_empty() {
+        return;
+    }
+
+    let now = now();
+    let mut buffer = String::new();
+    for record in records {
+        buffer.push_str(&format!("{}\t{}\t{}\t{}\t{}\n", now, pid, record.event,
+                                 escape(&record.file), record.name));
+    }
+
+    if let Some(parent) = Path::new(logfile).parent() {
+        if fs::create_dir_all(parent).is_err() {
+            return;
+        }
+    }
+
+    // A failure to write the log must never get in the way of the shell.
+    if let Ok(mut file) = OpenOptions::new().append(true).create(true).open(logfile) {
+        let _ = file.write_all(buffer.as_bytes());
+    }
+}
+
+// Return all records from the log file that match the filter, one line each.
+pub fn read_log(logfile: &str, filter: &Filter) -> Vec<String> {
+    let mut lines = vec![];
+    let file = match File::open(logfile) {
+        Ok(file) => file,
+        Err(_) => return lines
+    };
+
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        if fields.len() != 5 {
+            continue;
+        }
+
+        let timestamp: u64 = match fields[0].parse() {
+            Ok(timestamp) => timestamp,
+            Err(_) => continue
+        };
+        let (pid, event, file, name) = (fields[1], fields[2], unescape(fields[3]), fields[4]);
+
+        if let Some(since) = filter.since {
+            if timestamp < since {
+                continue;
+            }
+        }
+        if let Some(until) = filter.until {
+            if timestamp > until {
+                continue;
+            }
+        }
+        if let Some(ref p) = filter.pid {
+            if p != pid {
+                continue;
+            }
+        }
+        if let Some(ref f) = filter.file {
+            // Match either the file itself or the directory it is in.
+            let f = f.trim_end_matches('/');
+            if file != f && Path::new(&file).parent() != Some(Path::new(f)) {
+                continue;
+            }
+        }
+        if let Some(ref n) = filter.name {
+            if name.trim_end_matches("()").trim_end_matches('*') != n {
+                continue;
+            }
+        }
+
+        let line = format!("{}  {:>7}  {:<7}  {}  {}", format_time(timestamp), pid, event, file, name);
+        lines.push(line.trim_end().to_string());
+    }
+    lines
+}
+
+// Parse a point in time given on the command line. Accepted are seconds since the epoch,
+// a duration relative to now, e.g. 30s, 15m, 2h or 1d, and an UTC date in the form
+// YYYY-MM-DD, YYYY-MM-DDTHH:MM or YYYY-MM-DDTHH:MM:SS.
+pub fn parse_time(value: &str) -> Option<u64> {
+    if value.is_empty() {
+        return None;
+    }
+
+    if value.chars().all(|c| c.is_ascii_digit()) {
+        return value.parse().ok();
+    }
+
+    let (number, unit) = value.split_at(value.len() - 1);
+    if number.chars().all(|c| c.is_ascii_digit()) && !number.is_empty() {
+        let number: u64 = number.parse().ok()?;
+        let seconds = match unit {
+            "s" => number,
+            "m" => number * 60,
+            "h" => number * 3600,
+            "d" => number * 86400,
+            _ => return None
+        };
+        return Some(now().saturating_sub(seconds));
+    }
+
+    let (date, time) = match value.find(['T', ' ']) {
+        Some(i) => (&value[..i], &value[i+1..]),
+        None => (value, "00:00:00")
+    };
+
+    let date: Vec<i64> = date.split('-').map(|x| x.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<i64> = time.split(':').map(|x| x.parse().ok()).collect::<Option<_>>()?;
+    if date.len() != 3 || time.len() < 2 || time.len() > 3 {
+        return None;
+    }
+
+    let days = days_from_civil(date[0], date[1], date[2]);
+    let seconds = days * 86400 + time[0] * 3600 + time[1] * 60 + time.get(2).unwrap_or(&0);
+    if seconds < 0 {
+        None
+    } else {
+        Some(seconds as u64)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Format seconds since the epoch as an UTC date, e.g. 2021-11-07T14:32:00Z.
+pub fn format_time(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+            seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// Convert between days since the epoch and a date in the proleptic Gregorian calendar, see
+// http://howardhinnant.github.io/date_algorithms.html.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Filenames may contain tabs and newlines, which would break the record structure.
+pub fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+pub fn unescape(value: &str) -> String {
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some(c) => result.push(c),
+                None => result.push('\\')
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
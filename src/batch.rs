@@ -0,0 +1,127 @@
+// cdenv - batch.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Some commands load cdenv files without an interactive shell. They run a
+// script in a clean non-interactive bash that has cdenv.sh and the user's
+// settings loaded, but keeps its restore files in a temporary directory.
+
+use std::env;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Read};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use store::fnv1a;
+
+// The arguments of the script are the path of cdenv.sh, the temporary
+// directory and the cdenv executable, followed by the arguments for the
+// actual script. XDG_RUNTIME_DIR is only changed while cdenv.sh is sourced.
+// Nothing that sourcing cdenv.sh and the user's settings prints must end up
+// in the output of the script.
+const PRELUDE: &str = r#"
+__cdenv_sh="$1" __cdenv_tmp="$2" __cdenv_exe="$3"
+shift 3
+[[ -v XDG_RUNTIME_DIR ]] && __cdenv_xdg="$XDG_RUNTIME_DIR"
+export XDG_RUNTIME_DIR="$__cdenv_tmp"
+source "$__cdenv_sh" "" >/dev/null 2>&1
+if [[ -v __cdenv_xdg ]]; then XDG_RUNTIME_DIR="$__cdenv_xdg"; else unset XDG_RUNTIME_DIR; fi
+CDENV_EXEC="$__cdenv_exe"
+CDENV_CACHE="$__cdenv_tmp/cdenv"
+CDENV_LOG=
+CDENV_VERBOSE=0
+unset __cdenv_sh __cdenv_exe __cdenv_xdg
+$CDENV_EXEC gc --cache="$CDENV_CACHE" --pid=$$
+"#;
+
+// Return the path of cdenv.sh, which is either given explicitly, taken from
+// the environment or expected next to the executable.
+fn sh_path(sh: Option<&str>) -> Result<String, String> {
+    if let Some(sh) = sh {
+        return Ok(sh.to_string());
+    }
+    if let Ok(sh) = env::var("CDENV_SH") {
+        return Ok(sh);
+    }
+    let exe = env::current_exe().map_err(|err| format!("unable to find cdenv.sh: {}", err))?;
+    Ok(exe.with_file_name("cdenv.sh").display().to_string())
+}
+
+// A private temporary directory for a command, which is removed together with
+// its contents when it is dropped. It is created like mkdtemp(3) does: with a
+// random name and mode 0700, and creating it fails if the name already exists,
+// even as a symlink, so that another user cannot prepare it for us.
+pub struct TempDir {
+    path: PathBuf
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Result<TempDir, String> {
+        let mut builder = DirBuilder::new();
+        builder.mode(0o700);
+
+        for _ in 0..100 {
+            let path = env::temp_dir().join(format!("cdenv-{}-{:016x}", name, random()));
+            match builder.create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(format!("unable to create {}: {}", path.display(), err))
+            }
+        }
+        Err(format!("unable to create a temporary directory in {}", env::temp_dir().display()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Return a random number from the kernel, or one made up from the pid and the
+// time if /dev/urandom is not available.
+fn random() -> u64 {
+    let mut buffer = [0; 8];
+    match File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut buffer)) {
+        Ok(()) => u64::from_ne_bytes(buffer),
+        Err(_) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            fnv1a(format!("{}-{}", process::id(), now.as_nanos()).as_bytes())
+        }
+    }
+}
+
+// Return a bash command that runs the script after the prelude with the
+// cdenv.sh from sh_path(). The arguments for the script are added by the
+// caller.
+pub fn command(sh: Option<&str>, tmp: &Path, script: &str) -> Result<Command, String> {
+    let sh = sh_path(sh)?;
+    if !Path::new(&sh).exists() {
+        return Err(format!("cdenv.sh not found at {}, use --sh or set CDENV_SH", sh));
+    }
+
+    let mut command = Command::new("bash");
+    command.args(["--norc", "--noprofile", "-c", &format!("{}{}", PRELUDE, script), "bash", &sh])
+        .arg(tmp)
+        .arg(env::current_exe().map_err(|err| err.to_string())?);
+    Ok(command)
+}
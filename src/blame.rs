@@ -0,0 +1,126 @@
+// cdenv - blame.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt::Write;
+
+use environment::Snapshot;
+use manifest::{Action, Change, Kind, Manifest, Value, KINDS};
+use store::{fnv1a, Store};
+
+// Read the manifests of all loaded cdenv files in the order they were loaded.
+fn read_manifests(store: &Store, stack: &[String]) -> Vec<Manifest> {
+    stack.iter().filter_map(|file| store.read_manifest(file)).collect()
+}
+
+// Return true if the current value is still the one that a cdenv file set.
+fn is_unchanged(value: &Value, current: &Option<String>) -> bool {
+    value.fingerprint() == current.as_ref().map(|c| fnv1a(c.as_bytes()))
+}
+
+// List every name that has been changed by a loaded cdenv file together with
+// the file that changed it last.
+pub fn blame(store: &Store, stack: &[String], snapshot: &Snapshot) -> String {
+    let manifests = read_manifests(store, stack);
+    let mut text = String::new();
+
+    for kind in KINDS {
+        let mut names: Vec<&String> = manifests.iter()
+            .flat_map(|m| m.changes.iter())
+            .filter(|c| c.kind == *kind)
+            .map(|c| &c.name)
+            .collect();
+        names.sort();
+        names.dedup();
+
+        for name in names {
+            let (file, change) = manifests.iter()
+                .flat_map(|m| m.changes.iter().map(move |c| (&m.file, c)))
+                .rfind(|(_, c)| c.kind == *kind && c.name == *name)
+                .unwrap();
+
+            let current = snapshot.get(*kind, name);
+            writeln!(text, "{:<8}  {:<24}  {:<8}  {}{}", kind.as_str(), name, action_str(change), file,
+                           if is_unchanged(&change.new, &current) { "" } else { "  (changed since)" }).unwrap();
+        }
+    }
+    text
+}
+
+// Return the complete history of a name across all loaded cdenv files, None if
+// none of them changed it.
+pub fn why(store: &Store, stack: &[String], snapshot: &Snapshot, name: &str) -> Option<String> {
+    let manifests = read_manifests(store, stack);
+    let name = name.trim_end_matches("()").trim_end_matches('*');
+    let mut text = String::new();
+
+    for kind in KINDS {
+        let history: Vec<(&String, &Change)> = manifests.iter()
+            .flat_map(|m| m.changes.iter().map(move |c| (&m.file, c)))
+            .filter(|(_, c)| c.kind == *kind && c.name == name)
+            .collect();
+
+        if history.is_empty() {
+            continue;
+        }
+        if !text.is_empty() {
+            text.push('\n');
+        }
+
+        writeln!(text, "{} {}", kind.as_str(), name).unwrap();
+        for (file, change) in &history {
+            writeln!(text, "  {} by {}", action_str(change), file).unwrap();
+            write_value(&mut text, "    before: ", &change.old.describe());
+            write_value(&mut text, "    after:  ", &change.new.describe());
+        }
+
+        let (_, last) = history.last().unwrap();
+        let current = snapshot.get(*kind, name);
+        // Do not reveal the current value of a redacted variable.
+        let hidden = matches!(last.new, Value::Hidden(_));
+        write_value(&mut text, "  current:  ", &Value::new(current.as_ref(), hidden).describe());
+        if !is_unchanged(&last.new, &current) {
+            writeln!(text, "  changed since the last cdenv file was loaded").unwrap();
+        }
+    }
+
+    Some(text).filter(|text| !text.is_empty())
+}
+
+fn action_str(change: &Change) -> &'static str {
+    match (change.kind, change.action) {
+        (Kind::Option, Action::Add) => "set on",
+        (Kind::Option, _) => "set off",
+        (_, Action::Add) => "added",
+        (_, Action::Modify) => "modified",
+        (_, Action::Remove) => "removed"
+    }
+}
+
+// Write a possibly multi-line value with all lines indented to the same level.
+fn write_value(text: &mut String, label: &str, value: &str) {
+    let indent = " ".repeat(label.len());
+    for (i, line) in value.lines().enumerate() {
+        if i == 0 {
+            writeln!(text, "{}{}", label, line).unwrap();
+        } else {
+            writeln!(text, "{}{}", indent, line).unwrap();
+        }
+    }
+    if value.is_empty() {
+        writeln!(text, "{}", label.trim_end()).unwrap();
+    }
+}
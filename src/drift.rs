@@ -0,0 +1,115 @@
+// cdenv - drift.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use environment::{is_volatile, Snapshot};
+use manifest::{Kind, Value};
+use store::{fnv1a, Store};
+
+// The fingerprints of all names in an environment. Options that are off are
+// left out, just like unset variables.
+type Fingerprints = HashMap<(Kind, String), u64>;
+
+// Save the fingerprints of the environment at the time cdenv was initialized.
+// Only hashes are written, so the baseline never contains any values. An
+// existing baseline is kept, because cdenv.sh is sourced again on reload.
+pub fn write_baseline(store: &Store, snapshot: &Snapshot) -> io::Result<()> {
+    if store.baseline_path().exists() {
+        return Ok(());
+    }
+
+    let mut lines: Vec<String> = fingerprints(snapshot).iter()
+        .map(|((kind, name), hash)| format!("{}\t{}\t{:016x}\n", kind.as_str(), name, hash))
+        .collect();
+    lines.sort();
+
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+        .open(store.baseline_path())?;
+    file.write_all(lines.concat().as_bytes())
+}
+
+fn read_baseline(store: &Store) -> Option<Fingerprints> {
+    let text = fs::read_to_string(store.baseline_path()).ok()?;
+    let mut baseline = HashMap::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if let [kind, name, hash] = fields[..] {
+            if let (Some(kind), Ok(hash)) = (Kind::parse(kind), u64::from_str_radix(hash, 16)) {
+                baseline.insert((kind, name.to_string()), hash);
+            }
+        }
+    }
+    Some(baseline)
+}
+
+// Compare the current environment with the baseline and the changes of all
+// loaded cdenv files on top of it, and list everything that none of them
+// accounts for.
+pub fn drift(store: &Store, stack: &[String], snapshot: &Snapshot) -> Result<String, String> {
+    let mut expected = match read_baseline(store) {
+        Some(baseline) => baseline,
+        None => return Err(String::from("no baseline, cdenv was not initialized in this shell"))
+    };
+
+    for manifest in stack.iter().filter_map(|file| store.read_manifest(file)) {
+        for change in &manifest.changes {
+            let key = (change.kind, change.name.clone());
+            match fingerprint(change.kind, &change.new) {
+                Some(hash) => expected.insert(key, hash),
+                None => expected.remove(&key)
+            };
+        }
+    }
+
+    let current = fingerprints(snapshot);
+
+    let mut keys: Vec<&(Kind, String)> = expected.keys().chain(current.keys()).collect();
+    keys.sort_by(|a, b| (a.0.as_str(), &a.1).cmp(&(b.0.as_str(), &b.1)));
+    keys.dedup();
+
+    let mut text = String::new();
+    for key in keys {
+        let sign = match (expected.get(key), current.get(key)) {
+            (None, Some(_)) => '+',
+            (Some(_), None) => '-',
+            (Some(a), Some(b)) if a != b => '~',
+            _ => continue
+        };
+        text.push_str(&format!("{} {:<8} {}\n", sign, key.0.as_str(), key.1));
+    }
+    Ok(text)
+}
+
+// The fingerprint of the new value of a change. Options that are switched off
+// are treated as absent.
+fn fingerprint(kind: Kind, value: &Value) -> Option<u64> {
+    match (kind, value) {
+        (Kind::Option, Value::Plain(value)) if value == "off" => None,
+        _ => value.fingerprint()
+    }
+}
+
+fn fingerprints(snapshot: &Snapshot) -> Fingerprints {
+    snapshot.definitions().into_iter()
+        .filter(|d| d.kind != Kind::Variable || !is_volatile(&d.name))
+        .map(|d| ((d.kind, d.name), fnv1a(d.value.as_bytes())))
+        .collect()
+}
@@ -15,56 +15,435 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead};
 use std::collections::HashMap;
 use std::fs::File;
-use std::fs::OpenOptions;
+use std::path::Path;
 use std::io::prelude::*;
 
 use regex::{Regex,Captures};
 
-const EXCLUDE_VARS: &[&str] = &["_", "OLDPWD"];
+use audit::{self, Record};
+use manifest::{Action, Change, Kind, Location, Manifest, Value};
+use locate;
+use quoting::{quote, unquote, Quoting};
+use fish;
+use sh;
+use zsh;
+use CompareContext;
+
+// The call stack differs between the snapshots, because they are taken in
+// different places, and cannot be restored anyway.
+const EXCLUDE_VARS: &[&str] = &["_", "OLDPWD", "__cdenv_fd", "__cdenv_pwd", "BASH_ARGC", "BASH_ARGV",
+                                "BASH_LINENO", "BASH_SOURCE", "FUNCNAME", "LINENO"];
+
+// Variables that change on their own, so that comparing them between two
+// snapshots taken at different times is meaningless.
+const VOLATILE_VARS: &[&str] = &["_", "OLDPWD", "PWD", "BASH_ARGC", "BASH_ARGV", "BASH_COMMAND",
+                                 "BASH_LINENO", "BASH_REMATCH", "BASH_SOURCE", "BASH_SUBSHELL",
+                                 "COLUMNS", "EPOCHREALTIME", "EPOCHSECONDS", "FUNCNAME",
+                                 "HISTCMD", "LINENO", "LINES", "PIPESTATUS", "RANDOM", "SECONDS",
+                                 "SRANDOM"];
 
 enum LineState {
     Default,
     InVariableDef,
-    InArrayDef,
-    InFunctionDef
+    InAliasDef,
+    InFunctionDef,
+    InLocations,
+    InState
 }
 
-enum NameType {
-    Variable,
-    Function,
-    Alias
+// The variables, functions and aliases of a shell environment, and the
+// locations where the functions were defined if they are known. The working
+// directory, the background jobs and the history number are part of the shell
+// state that cdenv cannot restore, we only keep them to detect changes.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub vars: HashMap<String, String>,
+    pub funcs: HashMap<String, String>,
+    pub aliases: HashMap<String, String>,
+    pub locations: HashMap<String, (String, usize)>,
+    pub pwd: Option<String>,
+    pub jobs: Vec<String>,
+    pub history: Option<String>,
+    pub dialect: Dialect,
+    pub unparsed: Vec<String>
 }
 
-// Parse and compare two sets of shell environments.
-pub fn compare_environments(input: &str, restore: &str) {
-    let mut vars_a: HashMap<String, String> = HashMap::new();
-    let mut funcs_a: HashMap<String, String> = HashMap::new();
-    let mut alias_a: HashMap<String, String> = HashMap::new();
+// The shells that cdenv supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Sh
+}
+
+// The shell, version and mode that produced a snapshot, see c:snapshot.
+// Snapshots without this information are taken to be from a current bash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dialect {
+    pub shell: Shell,
+    pub major: u32,
+    pub minor: u32,
+    pub posix: bool
+}
+
+// A single variable, function, alias or option from a snapshot.
+pub struct Definition {
+    pub kind: Kind,
+    pub name: String,
+    pub value: String
+}
+
+// The changes between two snapshots.
+pub struct Diff {
+    pub changes: Vec<Change>
+}
+
+// The shell code for a diff: the statements that are evaluated right away and
+// the statements that undo the changes.
+pub struct Rendered {
+    pub output: String,
+    pub restore: String
+}
+
+impl Shell {
+    // Quote a string for the shell, fish treats backslashes in single quotes
+    // differently.
+    pub fn quote(&self, value: &str) -> String {
+        match self {
+            Shell::Fish => fish::quote(value),
+            _ => quote(value)
+        }
+    }
+
+    // Return the name of a helper function from the shell script, e.g.
+    // c.debug.
+    pub fn helper(&self, name: &str) -> String {
+        match self {
+            Shell::Sh => sh::helper(name),
+            _ => name.to_string()
+        }
+    }
+}
+
+impl Dialect {
+    pub const CURRENT: Dialect = Dialect { shell: Shell::Bash, major: 5, minor: 2, posix: false };
+
+    // Parse the header line of c:snapshot, e.g. "# cdenv bash 5.2 posix" or
+    // "# cdenv zsh 5.8.1". A POSIX shell has no version, "# cdenv sh".
+    pub fn parse(line: &str) -> Option<Dialect> {
+        let mut fields = line.strip_prefix("# cdenv ")?.split(' ');
+        let shell = match fields.next()? {
+            "bash" => Shell::Bash,
+            "zsh" => Shell::Zsh,
+            "fish" => Shell::Fish,
+            "sh" if fields.next().is_none() => {
+                return Some(Dialect { shell: Shell::Sh, major: 0, minor: 0, posix: true });
+            },
+            _ => return None
+        };
+        let mut version = fields.next()?.split('.');
+        let major = version.next()?.parse().ok()?;
+        let minor = version.next()?.parse().ok()?;
+        let posix = match fields.next() {
+            None => false,
+            Some("posix") => true,
+            Some(_) => return None
+        };
+        Some(Dialect { shell, major, minor, posix })
+    }
+
+    // Bash before 4.4 prints arrays as a compound assignment in single quotes,
+    // e.g. declare -a A='([0]="x")'.
+    pub fn quotes_arrays(&self) -> bool {
+        (self.major, self.minor) < (4, 4)
+    }
+
+    // In posix mode alias prints its definitions without the alias keyword.
+    pub fn bare_aliases(&self) -> bool {
+        self.posix
+    }
+}
+
+impl Snapshot {
+    // Parse the output of c:snapshot or { declare -p; declare -f; alias; },
+    // see parse_environment().
+    pub fn parse(text: &str) -> Snapshot {
+        let mut snapshot = Snapshot {
+            vars: HashMap::new(),
+            funcs: HashMap::new(),
+            aliases: HashMap::new(),
+            locations: HashMap::new(),
+            pwd: None,
+            jobs: vec![],
+            history: None,
+            dialect: Dialect::CURRENT,
+            unparsed: vec![]
+        };
+        match text.lines().next().and_then(Dialect::parse) {
+            Some(Dialect { shell: Shell::Zsh, .. }) => zsh::parse_environment(&mut text.as_bytes(), &mut snapshot),
+            Some(Dialect { shell: Shell::Fish, .. }) => fish::parse_environment(&mut text.as_bytes(), &mut snapshot),
+            Some(Dialect { shell: Shell::Sh, .. }) => sh::parse_environment(&mut text.as_bytes(), &mut snapshot),
+            _ => parse_environment(&mut text.as_bytes(), &mut snapshot)
+        }
+        snapshot
+    }
+
+    // Read a snapshot from a file or from stdin.
+    pub fn read(input: Option<&str>) -> io::Result<Snapshot> {
+        let mut text = String::new();
+        match input {
+            None => io::stdin().read_to_string(&mut text)?,
+            Some(filename) => File::open(filename)?.read_to_string(&mut text)?
+        };
+        Ok(Snapshot::parse(&text))
+    }
+
+    // Return a c.debug statement for every line that could not be parsed.
+    pub fn debug_statements(&self) -> String {
+        let shell = self.dialect.shell;
+        self.unparsed.iter()
+            .map(|line| format!("{} {}\n", shell.helper("c.debug"),
+                                shell.quote(&format!("unable to parse: {}", line))))
+            .collect()
+    }
+
+    // Return all variables, functions, aliases and options that are on, sorted
+    // by kind and name. BASHOPTS and SHELLOPTS are split up into options.
+    pub fn definitions(&self) -> Vec<Definition> {
+        let mut definitions = vec![];
+        for (name, value) in &self.vars {
+            if name == "BASHOPTS" || name == "SHELLOPTS" {
+                for option in value.split(':').filter(|x| !x.is_empty()) {
+                    definitions.push(Definition::new(Kind::Option, option, "on"));
+                }
+            } else {
+                definitions.push(Definition::new(Kind::Variable, name, value));
+            }
+        }
+        for (name, value) in &self.funcs {
+            definitions.push(Definition::new(Kind::Function, name, value));
+        }
+        for (name, value) in &self.aliases {
+            definitions.push(Definition::new(Kind::Alias, name, value));
+        }
+        definitions.sort_by(|a, b| (a.kind.as_str(), &a.name).cmp(&(b.kind.as_str(), &b.name)));
+        definitions
+    }
+
+    // Return the value of a name in the same form as it is stored in the
+    // manifest.
+    pub fn get(&self, kind: Kind, name: &str) -> Option<String> {
+        match kind {
+            Kind::Variable => self.vars.get(name).cloned(),
+            Kind::Function => self.funcs.get(name).cloned(),
+            Kind::Alias => self.aliases.get(name).cloned(),
+            Kind::Option => {
+                let on = ["BASHOPTS", "SHELLOPTS"].iter()
+                    .filter_map(|key| self.vars.get(*key))
+                    .any(|value| value.split(':').any(|option| option == name));
+                match option_value(on) {
+                    Value::Plain(value) => Some(value),
+                    _ => None
+                }
+            }
+        }
+    }
+}
+
+impl Definition {
+    fn new(kind: Kind, name: &str, value: &str) -> Definition {
+        Definition {
+            kind,
+            name: name.to_string(),
+            value: value.to_string()
+        }
+    }
+}
+
+impl Diff {
+    // Return the shell code for the changes, before is the snapshot the diff
+    // was made from.
+    pub fn render(&self, before: &Snapshot) -> Rendered {
+        render_changes(&self.changes, before, None)
+    }
+}
+
+// Return true if a variable changes on its own or is managed by cdenv itself.
+pub fn is_volatile(name: &str) -> bool {
+    VOLATILE_VARS.contains(&name) || name.starts_with("CDENV_") || name.starts_with("__cdenv_")
+}
 
-    let mut vars_b: HashMap<String, String> = HashMap::new();
-    let mut funcs_b: HashMap<String, String> = HashMap::new();
-    let mut alias_b: HashMap<String, String> = HashMap::new();
+// Parse and compare two sets of shell environments and return the shell code that applies the
+// changes. If a log file is given, every change is recorded in the audit log on behalf of the
+// cdenv file that was sourced. Variables whose name or value matches the redact pattern are not
+// written to the restore file.
+pub fn compare_environments(context: CompareContext, input: &str, output: Option<&str>)
+        -> io::Result<String> {
+    let read_error = |err: io::Error| io::Error::new(err.kind(), format!("unable to read the snapshots: {}", err));
+    let env_a = Snapshot::read(Some(input)).map_err(read_error)?;
+    let env_b = Snapshot::read(output).map_err(read_error)?;
+    compare_snapshots(context, env_a, env_b)
+}
 
-    parse_environment(Some(input), &mut vars_a, &mut funcs_a, &mut alias_a);
-    parse_environment(None, &mut vars_b, &mut funcs_b, &mut alias_b);
+// Return the shell code for two snapshots that are already parsed.
+pub fn compare_snapshots(context: CompareContext, mut env_a: Snapshot, mut env_b: Snapshot)
+        -> io::Result<String> {
+    let store = &context.store;
+    let file = context.file.as_str();
+    let shell = env_a.dialect.shell;
+
+    let mut code = env_a.debug_statements();
+    code.push_str(&env_b.debug_statements());
+
+    // The file has already been sourced at this point, so an invalid pattern
+    // must not stop us from writing the restore file.
+    let redact = match context.redact.as_ref().map(|pattern| Regex::new(pattern)) {
+        Some(Ok(regex)) => Some(regex),
+        Some(Err(_)) => {
+            code.push_str(&format!("{} {}\n", shell.helper("c.err"),
+                                   shell.quote(&format!("invalid CDENV_REDACT pattern {:?}, nothing is redacted",
+                                                        context.redact.as_ref().unwrap()))));
+            None
+        },
+        None => None
+    };
 
-    // We open the restore file in append mode, so that e.g. the on_leave()
+    // We open the restore file in append mode, so that e.g. the c.leave()
     // stdlib function can put code in it in advance.
-    let mut restore_file = OpenOptions::new().append(true).create(true).open(restore).unwrap();
+    let store_error = |err: io::Error| io::Error::new(err.kind(), format!("unable to write the restore file for {}: {}", file, err));
+    let mut restore_file = store.open(file).map_err(store_error)?;
+    let restore = store.path(file).map_err(store_error)?.display().to_string();
+    let redact = redact.as_ref().map(|regex| (regex, restore.as_str()));
 
     // Remove some names from the environment.
-    prune_unwanted_names(EXCLUDE_VARS, &mut vars_a);
-    prune_unwanted_names(EXCLUDE_VARS, &mut vars_b);
+    prune_unwanted_names(EXCLUDE_VARS, &mut env_a.vars);
+    prune_unwanted_names(EXCLUDE_VARS, &mut env_b.vars);
 
     // Compare the vars, funcs and alias sets and write statements to stdout
     // and the restore file.
-    compare_sets(&vars_a, &vars_b, &mut restore_file, NameType::Variable);
-    compare_sets(&funcs_a, &funcs_b, &mut restore_file, NameType::Function);
-    compare_sets(&alias_a, &alias_b, &mut restore_file, NameType::Alias);
+    let mut changes = diff_sets(&env_a.vars, &env_b.vars, Kind::Variable, redact.map(|(regex, _)| regex));
+    changes.append(&mut diff_sets(&env_a.funcs, &env_b.funcs, Kind::Function, None));
+    changes.append(&mut diff_sets(&env_a.aliases, &env_b.aliases, Kind::Alias, None));
+
+    let rendered = render_changes(&changes, &env_a, redact);
+    code.push_str(&rendered.output);
+    restore_file.write_all(rendered.restore.as_bytes()).map_err(store_error)?;
+
+    let locations = locate::find_locations(file, &changes, &env_b.locations);
+    code.push_str(&report_shadowing(&context, &changes, &locations, env_a.dialect.shell));
+    let leaks = find_leaks(file, &env_a, &env_b);
+    for leak in &leaks {
+        code.push_str(&format!("{} {}\n", shell.helper("c:leak"), shell.quote(&format!("{}: {}", file, leak))));
+    }
+
+    if let Some(ref logfile) = context.log {
+        let records: Vec<Record> = changes.iter()
+            .map(|change| Record::new(change.event(), file, &change.display_name()))
+            .collect();
+        audit::append(logfile, &context.pid, &records);
+    }
 
+    let mut manifest = Manifest::new(file, changes);
+    manifest.locations = locations;
+    manifest.leaks = leaks;
+    store.write_manifest(&manifest).map_err(store_error)?;
+    Ok(code)
+}
+
+// Find the changes to the shell state that the restore file cannot undo.
+fn find_leaks(file: &str, env_a: &Snapshot, env_b: &Snapshot) -> Vec<String> {
+    let mut leaks = vec![];
+
+    // c:safe_source sources the file in its own directory and changes back
+    // afterwards, which hides the cd but not its side effects.
+    let directory = Path::new(file).parent().map(|p| p.display().to_string());
+    if let (Some(pwd), Some(directory)) = (&env_b.pwd, directory) {
+        if *pwd != directory {
+            leaks.push(format!("changed the working directory to {}", pwd));
+        }
+    }
+
+    let jobs: Vec<&String> = env_b.jobs.iter().filter(|job| !env_a.jobs.contains(job)).collect();
+    if !jobs.is_empty() {
+        leaks.push(format!("started {} background job(s) that keep running after unloading",
+                           jobs.len()));
+    }
+
+    if env_a.vars.get("HISTFILE") != env_b.vars.get("HISTFILE") && env_a.history == env_b.history {
+        leaks.push(String::from("switched HISTFILE without reloading the history list"));
+    }
+
+    let mut readonly: Vec<&String> = env_b.vars.iter()
+        .filter(|(name, value)| is_readonly(value) &&
+                !env_a.vars.get(*name).map(|v| is_readonly(v)).unwrap_or(false))
+        .map(|(name, _)| name)
+        .collect();
+    readonly.sort();
+    for name in readonly {
+        leaks.push(format!("made {} readonly, it cannot be restored", name));
+    }
+
+    leaks
+}
+
+// Return true if a declare statement from the snapshot has the -r flag.
+fn is_readonly(value: &str) -> bool {
+    value.strip_prefix("declare -g")
+        .and_then(|rest| rest.split(' ').next())
+        .map(|flags| flags.contains('r'))
+        .unwrap_or(false)
+}
+
+// Warn about functions and aliases that were defined by one of the cdenv files
+// loaded before and are now redefined or removed. The warnings are shown if
+// CDENV_VERBOSE is at least at the shadow level.
+fn report_shadowing(context: &CompareContext, changes: &[Change], locations: &[Location],
+                    shell: Shell) -> String {
+    let mut code = String::new();
+    let earlier: Vec<Manifest> = context.loaded.iter()
+        .take_while(|file| **file != context.file)
+        .filter_map(|file| context.store.read_manifest(file))
+        .collect();
+
+    for change in changes {
+        if (change.kind != Kind::Function && change.kind != Kind::Alias) || change.action == Action::Add {
+            continue;
+        }
+
+        let outer = earlier.iter().rev().find(|manifest| {
+            manifest.changes.iter().any(|c| c.kind == change.kind && c.name == change.name &&
+                                        c.action != Action::Remove)
+        });
+
+        if let Some(outer) = outer {
+            let message = format!("{} {} from {} is {} by {}", change.kind.as_str(), change.name,
+                                  describe_location(&outer.file, &outer.locations, change),
+                                  if change.action == Action::Remove { "removed" } else { "redefined" },
+                                  describe_location(&context.file, locations, change));
+            code.push_str(&match shell {
+                Shell::Fish => format!("test $CDENV_VERBOSE -ge {}; and c.warn {}\n", context.shadow,
+                                       shell.quote(&message)),
+                Shell::Sh => format!("if [ \"$CDENV_VERBOSE\" -ge {} ]; then c_warn {}; fi\n", context.shadow,
+                                     shell.quote(&message)),
+                _ => format!("if [[ $CDENV_VERBOSE -ge {} ]]; then c.warn {}; fi\n", context.shadow,
+                             shell.quote(&message))
+            });
+        }
+    }
+    code
+}
+
+// Return file:line for the definition of a name if its location is known,
+// otherwise only the file.
+fn describe_location(file: &str, locations: &[Location], change: &Change) -> String {
+    match locations.iter().find(|l| l.kind == change.kind && l.name == change.name) {
+        Some(location) => format!("{}:{}", location.file, location.line),
+        None => file.to_string()
+    }
 }
 
 // Remove a set of names from the environment that change uncontrollably between invocations or
@@ -77,158 +456,186 @@ fn prune_unwanted_names(exclude: &'static [&'static str], set: &mut HashMap<Stri
     }
 }
 
-// Parse output of { declare -p; declare -f; alias; }.
+// Parse output of { declare -p; declare -f; alias; }, optionally followed by
+// a "# cdenv locations" line and the output of declare -F under extdebug, and
+// a "# cdenv state" line and the output of c:state. Lines that cannot be
+// parsed are collected in the snapshot.
 // Notes:
 // Here we create shell code that is later used to be sourced to restore the environment
 // prior to the changes. Because we source this code inside the c.load function we
 // have to add -g explicitly to declare all variables global.
-fn parse_environment(input: Option<&str>, set_var: &mut HashMap<String, String>,
-                     set_func: &mut HashMap<String, String>, set_alias: &mut HashMap<String, String>) {
-    let re_declare = Regex::new("^declare\\s+-+([iaAfxr]*)\\s+([a-zA-Z_][a-zA-Z0-9_]*)$").unwrap();
-    let re_var_start = Regex::new("^declare\\s+-+([ixr]*)\\s+([a-zA-Z_][a-zA-Z0-9_]*)=\"(.*)$").unwrap();
-    let re_array_start = Regex::new("^declare\\s+-([aAxr]+)\\s+([a-zA-Z_][a-zA-Z0-9_]*)=\\((.*)$").unwrap();
-    let re_function_start = Regex::new("^([a-zA-Z0-9_\\-:.]+)\\s*\\(\\)\\s*$").unwrap();
-    let re_function_end = Regex::new("^}\\s*$").unwrap();
-    let re_alias = Regex::new("^alias\\s+([a-zA-Z_][a-zA-Z0-9_\\-]*)='(.*)'$").unwrap();
+fn parse_environment(reader: &mut dyn BufRead, snapshot: &mut Snapshot) {
+    let re_declare = Regex::new("^declare\\s+-+([a-zA-Z]*)\\s+([a-zA-Z_][a-zA-Z0-9_]*)(=(.*))?$").unwrap();
+    let re_function_start = Regex::new("^(\\S+)\\s*\\(\\)\\s*$").unwrap();
+    let re_alias = Regex::new("^alias\\s+(?:--\\s+)?([^=\\s]+)=(.*)$").unwrap();
+    let re_bare_alias = Regex::new("^([^=\\s]+)=(.*)$").unwrap();
+    let re_location = Regex::new("^(\\S+) ([0-9]+) (.*)$").unwrap();
+
+    let mut dialect = Dialect::CURRENT;
+    let set_var = &mut snapshot.vars;
+    let set_func = &mut snapshot.funcs;
+    let set_alias = &mut snapshot.aliases;
 
     let mut line_state = LineState::Default;
+    let mut quoting = Quoting::default();
     let mut name = String::new();
     let mut body = String::new();
-
-    let reader: Box<dyn BufRead> = match input {
-        None => Box::new(BufReader::new(io::stdin())),
-        Some(filename) => Box::new(BufReader::new(File::open(filename).unwrap()))
+    let mut head = String::new();
+    let mut quoted_array = false;
+    let mut state = vec![];
+
+    // Store a complete variable definition, body is the value.
+    let mut add_variable = |name: &str, head: &str, body: &str, quoted_array: bool| {
+        let value = match quoted_array {
+            true => unquote(body.trim_end_matches('\n')).map(|value| format!("{}\n", value)),
+            false => None
+        };
+        set_var.insert(name.to_string(), format!("{}{}", head, value.as_deref().unwrap_or(body)));
     };
 
-    let lines = reader.lines();
-
     fn get_group(groups: &Captures, group: usize) -> String {
         groups.get(group).unwrap().as_str().to_string()
     }
 
-    for line in lines {
-        let mut line = line.unwrap().trim_end().to_string();
-
-        if let Some(groups) = re_declare.captures(&line) {
-            let opts = get_group(&groups, 1);
-            name = get_group(&groups, 2);
-            set_var.insert(name.clone(), format!("declare -g{} {}\n", opts, name));
+    // Values may contain carriage returns, so we cannot use lines().
+    for line in reader.split(b'\n') {
+        let line = String::from_utf8_lossy(&line.unwrap()).to_string();
 
-        } else if let Some(groups) = re_var_start.captures(&line) {
-            let opts = get_group(&groups, 1);
-            name = get_group(&groups, 2);
-            let value = get_group(&groups, 3);
-            if name == "BASHOPTS" || name == "SHELLOPTS" {
-                set_var.insert(name.clone(), value[..value.len()-1].to_string());
-                line_state = LineState::Default;
-            } else {
-                line = format!("declare -g{} {}=\"{}\n", opts, name, value);
-                if value.ends_with('"') && !value.ends_with("\\\"") {
-                    set_var.insert(name.clone(), line);
+        match line_state {
+            LineState::InVariableDef | LineState::InAliasDef => {
+                // Collect the lines of a multiline value.
+                quoting.feed(&line);
+                body.push_str(&line);
+                body.push('\n');
+                if !quoting.is_open() {
+                    match line_state {
+                        LineState::InAliasDef => { set_alias.insert(name.clone(), body.clone()); },
+                        _ => add_variable(&name, &head, &body, quoted_array)
+                    };
+                    body.clear();
+                    line_state = LineState::Default;
+                }
+                continue;
+            },
+            LineState::InFunctionDef => {
+                // Collect the lines in the function body. Trailing whitespace
+                // is only kept in strings and here-documents.
+                let verbatim = quoting.is_quoted();
+                quoting.feed(&line);
+                let line = if verbatim || quoting.is_quoted() { line.as_str() } else { line.trim_end() };
+                body.push_str(line);
+                body.push('\n');
+                if line == "}" && !quoting.is_quoted() {
+                    // The terminating line of a function definition.
+                    set_func.insert(name.clone(), body.clone());
+                    body.clear();
                     line_state = LineState::Default;
-                } else {
-                    body.push_str(&line);
-                    line_state = LineState::InVariableDef;
                 }
+                continue;
+            },
+            _ => {}
+        }
+
+        // Trailing whitespace may be part of a value.
+        let raw = line.as_str();
+        let line = line.trim_end();
+
+        if let Some(header) = Dialect::parse(line) {
+            dialect = header;
+            continue;
+        } else if line == "# cdenv locations" {
+            line_state = LineState::InLocations;
+            continue;
+        } else if line == "# cdenv state" {
+            line_state = LineState::InState;
+            continue;
+        }
+
+        if let LineState::InLocations = line_state {
+            if let Some(groups) = re_location.captures(line) {
+                let lineno = get_group(&groups, 2).parse().unwrap_or(0);
+                snapshot.locations.insert(get_group(&groups, 1), (get_group(&groups, 3), lineno));
             }
+            continue;
+        }
+
+        if let LineState::InState = line_state {
+            state.push(line.to_string());
+            continue;
+        }
+
+        quoting = Quoting::default();
 
-        } else if let Some(groups) = re_array_start.captures(&line) {
+        if let Some(groups) = re_declare.captures(raw) {
             let opts = get_group(&groups, 1);
             name = get_group(&groups, 2);
-            let value = get_group(&groups, 3);
-            line = format!("declare -g{} {}=({}\n", opts, name, value);
-            if value.ends_with(')') && !value.ends_with("\\)") {
-                set_var.insert(name.clone(), line);
-                line_state = LineState::Default;
-            } else {
-                body.push_str(&line);
-                line_state = LineState::InArrayDef;
+            match groups.get(4).map(|value| value.as_str()) {
+                None => {
+                    add_variable(&name, &format!("declare -g{} {}", opts, name), "\n", false);
+                },
+                Some(value) if name == "BASHOPTS" || name == "SHELLOPTS" => {
+                    add_variable(&name, "", value.trim_matches('"'), false);
+                },
+                Some(value) => {
+                    quoting.feed(value);
+                    head = format!("declare -g{} {}=", opts, name);
+                    body = format!("{}\n", value);
+                    quoted_array = dialect.quotes_arrays() && (opts.contains('a') || opts.contains('A'));
+                    if quoting.is_open() {
+                        line_state = LineState::InVariableDef;
+                    } else {
+                        add_variable(&name, &head, &body, quoted_array);
+                        body.clear();
+                    }
+                }
             }
 
-        } else if let Some(groups) = re_function_start.captures(&line) {
+        } else if let Some(groups) = re_function_start.captures(line) {
             // Parse the first line of a function definition.
             name = get_group(&groups, 1);
-            body.push_str(&line);
-            body.push('\n');
+            body = format!("{}\n", line);
             line_state = LineState::InFunctionDef;
 
-        } else if let Some(groups) = re_alias.captures(&line) {
+        } else if let Some(groups) = re_alias.captures(raw).or_else(|| {
+            if dialect.bare_aliases() { re_bare_alias.captures(raw) } else { None }
+        }) {
             name = get_group(&groups, 1);
-            body = get_group(&groups, 2);
-            set_alias.insert(name.clone(), format!("alias {}='{}'\n", name, body));
-
-        } else if re_function_end.is_match(&line) {
-            // Parse the terminating line of a function definition.
-            body.push_str(&line);
-            body.push('\n');
-            set_func.insert(name.clone(), body.clone());
-            body.clear();
-            line_state = LineState::Default;
+            let value = get_group(&groups, 2);
+            quoting.feed(&value);
+            body = format!("alias {}={}\n", name, value);
+            if quoting.is_open() {
+                line_state = LineState::InAliasDef;
+            } else {
+                set_alias.insert(name.clone(), body.clone());
+                body.clear();
+            }
 
         } else {
-            match line_state {
-                LineState::InVariableDef => {
-                    // Collect the lines of a multiline variable.
-                    body.push_str(&line);
-                    body.push('\n');
-                    if line.ends_with('"') && !line.ends_with("\\\"") {
-                        set_var.insert(name.clone(), body.clone());
-                        body.clear();
-                        line_state = LineState::Default;
-                    }
-                },
-                LineState::InArrayDef => {
-                    // Collect the lines of a multiline variable.
-                    body.push_str(&line);
-                    body.push('\n');
-                    if line.ends_with(')') && !line.ends_with("\\)") {
-                        set_var.insert(name.clone(), body.clone());
-                        body.clear();
-                        line_state = LineState::Default;
-                    }
-                },
-                LineState::InFunctionDef => {
-                    // Collect the lines in the function body.
-                    body.push_str(&line);
-                    body.push('\n');
-                },
-                LineState::Default => {
-                    let mut line = line.trim_end().to_string();
-                    // Escape backslashes.
-                    line = line.replace("\\", "\\\\");
-                    // You can't use a single quote in a single-quoted string even if it is
-                    // escaped with a backslash. The work-around is to close the single-quoted
-                    // string, add a single-quote and open it again: 'foo'\''bar' or
-                    // 'foo'"'"'bar'.
-                    line = line.replace("'", "'\\''");
-                    println!("c.debug 'unable to parse: {}'", line);
-                }
-            }
+            snapshot.unparsed.push(line.to_string());
         }
     }
-}
 
-// Compare the two sets set_a and set_b and write debug statements to stdout
-// and restore statements to the restore file.
-fn compare_sets(set_a: &HashMap<String, String>, set_b: &HashMap<String, String>,
-              restore_file: &mut File, name_type: NameType) {
+    for line in state {
+        parse_state(&line, snapshot);
+    }
+    snapshot.dialect = dialect;
+}
 
-    let suffix;
-    let unset;
-    match name_type {
-        NameType::Variable => {
-            suffix = "";
-            unset = "unset";
-        },
-        NameType::Function => {
-            suffix = "()";
-            unset = "unset -f";
-        },
-        NameType::Alias => {
-            suffix = "*";
-            unset = "unalias";
-        }
+// Parse a line from the "# cdenv state" section of a snapshot, see c:state.
+pub fn parse_state(line: &str, snapshot: &mut Snapshot) {
+    match line.split_once(' ') {
+        Some(("pwd", value)) => snapshot.pwd = Some(value.to_string()),
+        Some(("job", value)) => snapshot.jobs.push(value.to_string()),
+        Some(("history", value)) => snapshot.history = Some(value.to_string()),
+        _ => {}
     }
+}
+
+// Compare the two sets set_a and set_b and return a sorted list of the
+// changes. Changes to BASHOPTS and SHELLOPTS are split up into changes of the
+// single options. Names or values that match the redact pattern are hidden.
+fn diff_sets(set_a: &HashMap<String, String>, set_b: &HashMap<String, String>, kind: Kind,
+             redact: Option<&Regex>) -> Vec<Change> {
 
     // Create a sorted list of all keys. There may be more idiomatic ways to do this.
     let mut keys: Vec<String> = vec![];
@@ -242,52 +649,237 @@ fn compare_sets(set_a: &HashMap<String, String>, set_b: &HashMap<String, String>
     }
     keys.sort();
 
+    let mut changes = vec![];
+
     for key in keys {
+        // Values of redacted variables are not even written to the manifest.
+        let hidden = match redact {
+            Some(regex) => set_a.get(&key).map(|v| is_redacted(regex, &key, v)).unwrap_or(false) ||
+                set_b.get(&key).map(|v| is_redacted(regex, &key, v)).unwrap_or(false),
+            None => false
+        };
+        let old = Value::new(set_a.get(&key), hidden);
+        let new = Value::new(set_b.get(&key), hidden);
+
         if !set_a.contains_key(&key) {
-            // A name was added.
-            println!("c.debug 'add     {}{}'", key, suffix);
-            write(restore_file, format!("# {}\n", key));
-            write(restore_file, format!("c.debug 'remove  {}{}'\n", key, suffix));
-            write(restore_file, format!("{} {}\n", unset, key));
+            changes.push(Change::new(Action::Add, kind, &key, old, new));
 
         } else if !set_b.contains_key(&key) {
-            // A name was removed.
-            println!("c.debug 'remove  {}{}'", key, suffix);
-            write(restore_file, format!("c.debug 'restore {}{}'\n", key, suffix));
-            write(restore_file, set_a.get(&key).unwrap().to_string());
+            changes.push(Change::new(Action::Remove, kind, &key, old, new));
 
         } else if set_a.get(&key) != set_b.get(&key) {
-            if matches!(name_type, NameType::Variable) && (key == "BASHOPTS" || key == "SHELLOPTS") {
+            if kind == Kind::Variable && (key == "BASHOPTS" || key == "SHELLOPTS") {
                 let old:Vec<_> = set_a.get(&key).unwrap().split(':').collect();
                 let new:Vec<_> = set_b.get(&key).unwrap().split(':').collect();
                 for key in &old {
-                    if !new.contains(&key) {
-                        println!("c.debug 'set off {}{}'", key, suffix);
-                        write(restore_file, format!("# {}\n", key));
-                        write(restore_file, format!("c.debug 'set on  {}{}'\n", key, suffix));
-                        write(restore_file, format!("shopt -s {} 2>/dev/null || shopt -so {}\n", key, key));
+                    if !new.contains(key) {
+                        changes.push(Change::new(Action::Remove, Kind::Option, key, option_value(true),
+                                                 option_value(false)));
                     }
                 }
                 for key in &new {
-                    if !old.contains(&key) {
-                        println!("c.debug 'set on  {}{}'", key, suffix);
-                        write(restore_file, format!("# {}\n", key));
-                        write(restore_file, format!("c.debug 'set off {}{}'\n", key, suffix));
-                        write(restore_file, format!("shopt -u {} 2>/dev/null || shopt -uo {}\n", key, key));
+                    if !old.contains(key) {
+                        changes.push(Change::new(Action::Add, Kind::Option, key, option_value(false),
+                                                 option_value(true)));
                     }
                 }
             } else {
+                changes.push(Change::new(Action::Modify, kind, &key, old, new));
+            }
+        }
+    }
+
+    changes
+}
+
+// The name and the value of a variable are matched separately.
+fn is_redacted(regex: &Regex, key: &str, statement: &str) -> bool {
+    regex.is_match(key) || regex.is_match(&variable_value(key, statement))
+}
+
+// Return the value of a variable from the statement in a snapshot, without
+// the quotes, so that the redact pattern can be anchored at the value, e.g.
+// ^sk-. The elements of arrays and fish lists are returned as they are listed.
+fn variable_value(key: &str, statement: &str) -> String {
+    let statement = statement.trim_end_matches('\n');
+    // A POSIX shell lists exported variables with an extra export statement.
+    let statement = statement.strip_suffix(&format!("\nexport {}", key)).unwrap_or(statement);
+    let value = match (statement.find(&format!(" {}=", key)), statement.strip_prefix(&format!("{}=", key))) {
+        (_, Some(value)) => value,
+        (Some(index), _) => &statement[index + key.len() + 2..],
+        // fish: set -g NAME 'value' ...
+        (None, None) => match statement.find(&format!(" {} ", key)) {
+            Some(index) => &statement[index + key.len() + 2..],
+            None => return String::new()
+        }
+    };
+
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut result = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some(next @ ('"' | '\\' | '$' | '`'))) => {
+                    result.push(next);
+                    chars.next();
+                },
+                (c, _) => result.push(c)
+            }
+        }
+        result
+    } else if value.starts_with("$'") && value.ends_with('\'') && value.len() >= 3 {
+        value[2..value.len() - 1].to_string()
+    } else if value.starts_with('\'') && value.contains("\\'") {
+        // fish escapes quotes with a backslash.
+        value[1..value.len() - 1].replace("\\'", "'").replace("\\\\", "\\")
+    } else {
+        unquote(value).unwrap_or_else(|| value.to_string())
+    }
+}
+
+// Compare two snapshots and return the changes to variables, functions,
+// aliases and options.
+pub fn diff(env_a: &Snapshot, env_b: &Snapshot) -> Diff {
+    let mut changes = diff_sets(&env_a.vars, &env_b.vars, Kind::Variable, None);
+    changes.append(&mut diff_sets(&env_a.funcs, &env_b.funcs, Kind::Function, None));
+    changes.append(&mut diff_sets(&env_a.aliases, &env_b.aliases, Kind::Alias, None));
+    Diff { changes }
+}
+
+// Create the debug statements for the changes and the restore statements that
+// undo them. before is the snapshot the changes were made from.
+fn render_changes(changes: &[Change], before: &Snapshot,
+                  redact: Option<(&Regex, &str)>) -> Rendered {
+    let mut output = String::new();
+    let mut restore = String::new();
+    let shell = before.dialect.shell;
+
+    for change in changes {
+        let key = &change.name;
+        let suffix = match change.kind {
+            Kind::Variable | Kind::Option => "",
+            Kind::Function => "()",
+            Kind::Alias => "*"
+        };
+        let debug = |message: &str| format!("{} {}\n", shell.helper("c.debug"),
+                                            shell.quote(&format!("{} {}{}", message, key, suffix)));
+
+        match (change.kind, change.action) {
+            (Kind::Option, Action::Remove) => {
+                output.push_str(&debug("set off"));
+                restore.push_str(&format!("# {}\n", key));
+                restore.push_str(&debug("set on "));
+                restore.push_str(&option_statement(shell, key, true));
+            },
+            (Kind::Option, _) => {
+                output.push_str(&debug("set on "));
+                restore.push_str(&format!("# {}\n", key));
+                restore.push_str(&debug("set off"));
+                restore.push_str(&option_statement(shell, key, false));
+            },
+            (_, Action::Add) => {
+                // A name was added.
+                output.push_str(&debug("add    "));
+                restore.push_str(&format!("# {}\n", key));
+                restore.push_str(&debug("remove "));
+                restore.push_str(&unset_statement(shell, change.kind, key, &change.new));
+            },
+            (_, Action::Remove) => {
+                // A name was removed.
+                output.push_str(&debug("remove "));
+                restore.push_str(&debug("restore"));
+                restore.push_str(&restore_statement(shell, change.kind, key, &before.get(change.kind, key).unwrap(),
+                                                    redact, &mut output));
+            },
+            (_, Action::Modify) => {
                 // The value of a name was modified.
-                println!("c.debug 'modify  {}{}'", key, suffix);
-                write(restore_file, format!("# {}\n", key));
-                write(restore_file, format!("c.debug 'restore {}{}'\n", key, suffix));
-                write(restore_file, format!("{} {}\n", unset, key));
-                write(restore_file, set_a.get(&key).unwrap().to_string());
+                output.push_str(&debug("modify "));
+                restore.push_str(&format!("# {}\n", key));
+                restore.push_str(&debug("restore"));
+                restore.push_str(&unset_statement(shell, change.kind, key, &change.new));
+                restore.push_str(&restore_statement(shell, change.kind, key, &before.get(change.kind, key).unwrap(),
+                                                    redact, &mut output));
             }
         }
     }
+
+    Rendered { output, restore }
+}
+
+pub fn option_value(on: bool) -> Value {
+    Value::Plain(String::from(if on { "on" } else { "off" }))
 }
 
-fn write(file: &mut File, message: String) {
-    file.write_all(message.as_bytes()).expect("write failed!");
+// Return the statement that switches an option on or off. Bash has two sets
+// of options, shopt -o covers the ones from set -o.
+fn option_statement(shell: Shell, key: &str, on: bool) -> String {
+    match (shell, on) {
+        (Shell::Zsh, _) => zsh::option_statement(key, on),
+        // fish has no options, and they are not tracked for POSIX shells.
+        (Shell::Fish, _) | (Shell::Sh, _) => String::new(),
+        (_, true) => format!("shopt -s {} 2>/dev/null || shopt -so {}\n", key, key),
+        (_, false) => format!("shopt -u {} 2>/dev/null || shopt -uo {}\n", key, key)
+    }
 }
+
+// Return the statement that removes a name. Function and alias names may
+// contain almost any character, even a leading dash. value is the current
+// definition of the name.
+fn unset_statement(shell: Shell, kind: Kind, key: &str, value: &Value) -> String {
+    match shell {
+        Shell::Zsh => return zsh::unset_statement(kind, key, value),
+        Shell::Fish => return fish::unset_statement(kind, key),
+        Shell::Bash | Shell::Sh => {}
+    }
+    match kind {
+        Kind::Function => format!("unset -f -- {}\n", quote(key)),
+        Kind::Alias => format!("unalias -- {}\n", quote(key)),
+        _ => format!("unset {}\n", key)
+    }
+}
+
+// Return the statement that defines a name from its value in the snapshot.
+// declare -f and alias print names as they are, which is not always valid
+// input, e.g. for a function named a=b or an alias named a*b. The snapshots
+// from the other shells are valid input as they are.
+fn define_statement(shell: Shell, kind: Kind, key: &str, value: &str) -> String {
+    if shell != Shell::Bash {
+        return value.to_string();
+    }
+    match kind {
+        Kind::Function => format!("function {}", value),
+        Kind::Alias => {
+            let prefix = format!("alias {}=", key);
+            match value.strip_prefix(&prefix) {
+                Some(value) => format!("alias -- {}={}", quote(key), value),
+                None => value.to_string()
+            }
+        },
+        _ => value.to_string()
+    }
+}
+
+// Return the statement that restores a name to its old value. If the name or
+// the value matches the redact pattern, the statement is stored in the
+// CDENV_REDACTED array in the shell's memory instead of the restore file, and
+// the restore file only gets a reference to it.
+fn restore_statement(shell: Shell, kind: Kind, key: &str, value: &str, redact: Option<(&Regex, &str)>,
+                     output: &mut String) -> String {
+    let statement = define_statement(shell, kind, key, value);
+    match redact {
+        Some((regex, restore)) if shell == Shell::Fish && is_redacted(regex, key, value) => {
+            fish::redact_statement(&format!("{}:{}", restore, key), &statement, output)
+        },
+        Some((regex, restore)) if shell == Shell::Sh && is_redacted(regex, key, value) => {
+            sh::redact_statement(&format!("{}:{}", restore, key), &statement, output)
+        },
+        Some((regex, restore)) if is_redacted(regex, key, value) => {
+            let index = quote(&format!("{}:{}", restore, key));
+            output.push_str(&format!("CDENV_REDACTED[{}]={}\n", index, quote(&statement)));
+            format!("eval \"${{CDENV_REDACTED[{}]}}\"\nunset 'CDENV_REDACTED[{}]'\n",
+                    index, index.replace('\'', "'\\''"))
+        },
+        _ => statement
+    }
+}
+
@@ -0,0 +1,53 @@
+// cdenv - exec.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::os::unix::process::CommandExt;
+
+use batch;
+
+// The script loads all cdenv files for a directory and replaces itself with
+// the command, which runs in the original working directory. Output of the
+// cdenv files goes to stderr, so that it does not mix with the output of the
+// command. The restore files are not needed, because nothing is unloaded, and
+// the temporary directory is removed by the script, because this process is
+// gone by then.
+const SCRIPT: &str = r#"
+trap 'rm -rf "$__cdenv_tmp"' EXIT
+__cdenv_cwd="$PWD"
+builtin cd "$1" || exit 2
+cdenv update >&2
+builtin cd "$__cdenv_cwd" || exit 2
+rm -rf "$__cdenv_tmp"
+trap - EXIT
+unset __cdenv_cwd __cdenv_tmp
+shift
+exec "$@"
+"#;
+
+// Run a command in the environment that the cdenv files for a directory
+// create. This process is replaced, so the exit status of the command is
+// passed through. Only an error is ever returned.
+pub fn exec(sh: Option<&str>, dir: &str, command: &[String]) -> String {
+    let tmp = match batch::TempDir::new("exec") {
+        Ok(tmp) => tmp,
+        Err(err) => return err
+    };
+    match batch::command(sh, tmp.path(), SCRIPT) {
+        Ok(mut bash) => format!("unable to run bash: {}", bash.arg(dir).args(command).exec()),
+        Err(err) => err
+    }
+}
@@ -0,0 +1,183 @@
+// cdenv - export.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::process::Stdio;
+
+use batch;
+use json;
+use quoting::quote;
+
+pub const FORMATS: &[&str] = &["dotenv", "json", "systemd", "docker", "export"];
+
+// Variables that bash or env set on their own.
+const EXCLUDE_VARS: &[&str] = &["_", "PWD", "OLDPWD", "SHLVL"];
+
+// The environment that the files are loaded in. It must not depend on the
+// shell that cdenv export is run from, e.g. one that has the same files
+// loaded already, otherwise their changes would not show up in the output.
+const BASE_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+const BASE_VARS: &[&str] = &["HOME", "USER", "LOGNAME", "LANG"];
+
+// The script writes the exported variables before and after loading all cdenv
+// files for a directory to stdout, separated by an empty entry. Everything
+// else goes to stderr.
+const SCRIPT: &str = r#"
+builtin cd "$1" || exit 2
+exec 3>&1 >&2
+env -0 >&3
+printf '\0' >&3
+cdenv update
+env -0 >&3
+"#;
+
+// The exported variables that the cdenv files for a directory add, modify or
+// remove. None stands for a removed variable.
+pub type Delta = BTreeMap<String, Option<String>>;
+
+// Load the cdenv files for a directory and return the delta.
+pub fn export(sh: Option<&str>, dir: &str) -> Result<Delta, String> {
+    let tmp = batch::TempDir::new("export")?;
+
+    let mut command = batch::command(sh, tmp.path(), SCRIPT)?;
+    command.env_clear().env("PATH", BASE_PATH);
+    for name in BASE_VARS {
+        if let Some(value) = env::var_os(name) {
+            command.env(name, value);
+        }
+    }
+    let output = command
+        .arg(dir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("unable to run bash: {}", err))?;
+    drop(tmp);
+
+    let (before, after) = match read_env(&output.stdout) {
+        Some((before, after)) if output.status.success() => (before, after),
+        _ => return Err(format!("unable to load {}", dir))
+    };
+
+    let mut delta = Delta::new();
+    for (name, value) in &after {
+        if before.get(name) != Some(value) {
+            delta.insert(name.clone(), Some(value.clone()));
+        }
+    }
+    for name in before.keys() {
+        if !after.contains_key(name) {
+            delta.insert(name.clone(), None);
+        }
+    }
+    Ok(delta)
+}
+
+// Format the delta in one of the formats. Variables that cannot be expressed
+// in the format are left out, and a warning is returned for each of them.
+pub fn format_delta(delta: &Delta, format: &str) -> (String, Vec<String>) {
+    if format == "json" {
+        let fields: Vec<(&str, String)> = delta.iter()
+            .map(|(name, value)| (name.as_str(), value.as_ref().map(|v| json::string(v))
+                                                       .unwrap_or_else(|| String::from("null"))))
+            .collect();
+        return (format!("{}\n", json::object(&fields)), vec![]);
+    }
+
+    let mut output = String::new();
+    let mut warnings = vec![];
+    for (name, value) in delta {
+        match (format, value) {
+            ("export", Some(value)) => output.push_str(&format!("export {}={}\n", name, quote(value))),
+            ("export", None) => output.push_str(&format!("unset {}\n", name)),
+            ("dotenv", Some(value)) => output.push_str(&format!("{}=\"{}\"\n", name, dotenv_escape(value))),
+            ("systemd", Some(value)) => output.push_str(&format!("{}=\"{}\"\n", name, systemd_escape(value))),
+            ("docker", Some(value)) if value.contains('\n') => {
+                warnings.push(format!("{} cannot be exported for docker, its value contains a newline", name));
+            },
+            ("docker", Some(value)) => output.push_str(&format!("{}={}\n", name, value)),
+            (_, None) => {
+                warnings.push(format!("{} is removed, which cannot be expressed in {} format", name, format));
+            },
+            _ => unreachable!()
+        }
+    }
+    (output, warnings)
+}
+
+// Read the two dumps from env -0. Functions that are exported with export -f
+// show up as BASH_FUNC_name%%, and env may carry other names that are no
+// variables either. Both are left out.
+fn read_env(data: &[u8]) -> Option<(BTreeMap<String, String>, BTreeMap<String, String>)> {
+    let mut dumps = vec![BTreeMap::new()];
+    let data = data.strip_suffix(b"\0")?;
+    for entry in data.split(|b| *b == 0) {
+        if entry.is_empty() {
+            dumps.push(BTreeMap::new());
+            continue;
+        }
+        let entry = String::from_utf8_lossy(entry);
+        if let Some((name, value)) = entry.split_once('=') {
+            if is_identifier(name) && !EXCLUDE_VARS.contains(&name) {
+                dumps.last_mut().unwrap().insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+    if dumps.len() != 2 {
+        return None;
+    }
+    let after = dumps.pop().unwrap();
+    let before = dumps.pop().unwrap();
+    Some((before, after))
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Most dotenv parsers support C-style escapes and expand variables in double
+// quotes.
+fn dotenv_escape(value: &str) -> String {
+    let mut result = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '"' => result.push_str("\\\""),
+            '$' => result.push_str("\\$"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c => result.push(c)
+        }
+    }
+    result
+}
+
+// systemd's EnvironmentFile= follows the shell rules for double quotes, so
+// newlines are kept literally.
+fn systemd_escape(value: &str) -> String {
+    let mut result = String::new();
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
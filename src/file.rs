@@ -15,60 +15,283 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::env;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
-use std::time::UNIX_EPOCH;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::path::Path;
 use std::iter::Iterator;
-use glob::glob;
-use Context;
+use glob::{glob, Pattern};
+use audit::{self, Record};
+use environment::Shell;
+use fish;
+use quoting::quote;
+use json;
+use store;
 
+// Why a file is unloaded or loaded.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Reason {
+    Reload,
+    Removed,
+    Changed,
+    Diverged,
+    Left,
+    Entered,
+    Global,
+    Library
+}
 
-pub fn list_paths(context: Context, pwd: &str, loaded: &[String]) {
-    let (unload, load) = list_dirs(context, pwd, loaded);
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Reload => "reload",
+            Reason::Removed => "removed",
+            Reason::Changed => "changed",
+            Reason::Diverged => "diverged",
+            Reason::Left => "left",
+            Reason::Entered => "entered",
+            Reason::Global => "global",
+            Reason::Library => "library"
+        }
+    }
 
-    println!("local -a unload=(");
-    for name in unload {
-        println!("  {:?}", name);
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Reason::Reload => "reload was requested",
+            Reason::Removed => "removed from disk",
+            Reason::Changed => "changed on disk",
+            Reason::Diverged => "a file before it is reloaded",
+            Reason::Left => "its directory is left",
+            Reason::Entered => "its directory is entered",
+            Reason::Global => "global file from the home directory",
+            Reason::Library => "library from CDENV_PATH"
+        }
     }
-    println!(")");
+}
+
+// The result of comparing the files that are loaded with the files that are
+// needed for a directory: the new stack, the files to unload in the order in
+// which they are unloaded and the files to load in the order in which they are
+// loaded.
+pub struct Plan {
+    pub stack: Vec<String>,
+    pub unload: Vec<(String, Reason)>,
+    pub load: Vec<(String, Reason)>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub tag: u64
+}
 
-    println!("local -a load=(");
-    for name in load {
-        println!("  {:?}", name);
+// The settings that decide which cdenv files are needed for a directory.
+pub struct DiscoveryConfig {
+    pub global: bool,
+    pub reload: bool,
+    pub autoreload: bool,
+    pub tag: u64,
+    pub file: String,
+    pub path: String,
+    pub home: String,
+    pub shell: Shell
+}
+
+// The shell scripts in the CDENV_PATH directories. A directory is only read
+// again if its modification time changes, which is the case whenever a file is
+// added, removed or renamed. This is only worth it for cdenv serve, every other
+// command starts with an empty cache. Directories that changed within the last
+// two seconds are not cached, some file systems only store whole seconds.
+#[derive(Default)]
+pub struct LibraryCache {
+    directories: HashMap<String, (SystemTime, Vec<String>)>
+}
+
+impl LibraryCache {
+    fn list(&mut self, directory: &str) -> Vec<String> {
+        let mtime = fs::metadata(directory).and_then(|metadata| metadata.modified()).ok();
+        match (mtime, self.directories.get(directory)) {
+            (Some(mtime), Some((cached, files))) if mtime == *cached => files.clone(),
+            _ => {
+                let files: Vec<String> = glob(&format!("{}/*.sh", Pattern::escape(directory)))
+                    .into_iter().flatten().flatten()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                let settled = mtime.and_then(|mtime| mtime.elapsed().ok())
+                    .map(|age| age.as_secs() >= 2)
+                    .unwrap_or(false);
+                if let (Some(mtime), true) = (mtime, settled) {
+                    self.directories.insert(directory.to_string(), (mtime, files.clone()));
+                }
+                files
+            }
+        }
     }
-    println!(")");
 }
 
-fn get_mtime(path: &str) -> u64 {
-    let metadata = fs::metadata(path).unwrap();
+impl Plan {
+    // Return the shell code that c:update evaluates.
+    pub fn render(&self, config: &DiscoveryConfig) -> String {
+        let mut code = String::new();
+        let shell = config.shell;
+        // fish cannot declare local variables for the caller of source and a
+        // POSIX shell has no arrays, so for them the lists go to global
+        // variables that the update function removes afterwards. A POSIX
+        // shell gets them as lines.
+        let mut array = |name: &str, global_name: &str, values: &mut dyn Iterator<Item=&String>| {
+            match shell {
+                Shell::Fish => {
+                    code.push_str(&format!("set -g {}", global_name));
+                    for value in values {
+                        code.push_str(&format!(" {}", fish::quote(value)));
+                    }
+                    code.push('\n');
+                },
+                Shell::Sh => {
+                    let lines: Vec<&str> = values.map(|value| value.as_str()).collect();
+                    code.push_str(&format!("{}={}\n", global_name, quote(&lines.join("\n"))));
+                },
+                _ => {
+                    code.push_str(&format!("{}=(\n", name));
+                    for value in values {
+                        code.push_str(&format!("  {}\n", quote(value)));
+                    }
+                    code.push_str(")\n");
+                }
+            }
+        };
 
-    if let Ok(time) = metadata.modified() {
-        time.duration_since(UNIX_EPOCH).unwrap().as_secs()
-    } else {
-        0
+        // The new CDENV_STACK value with all found filenames.
+        array("CDENV_STACK", "CDENV_STACK", &mut self.stack.iter());
+
+        if !config.reload && config.autoreload {
+            // Some helpful debug messages about which files changed.
+            array("removed", "__cdenv_removed", &mut self.removed.iter());
+            array("changed", "__cdenv_changed", &mut self.changed.iter());
+        }
+
+        array("local -a unload", "__cdenv_unload", &mut self.unload.iter().map(|(name, _)| name));
+        array("local -a load", "__cdenv_load", &mut self.load.iter().map(|(name, _)| name));
+
+        if !config.reload && config.autoreload {
+            match shell {
+                Shell::Fish => code.push_str(&format!("set -g CDENV_TAG {}\n", self.tag)),
+                _ => code.push_str(&format!("CDENV_TAG={}\n", self.tag))
+            }
+        }
+        code
+    }
+
+    // Describe which files would be unloaded and loaded and why, for cdenv
+    // plan.
+    pub fn describe(&self, as_json: bool) -> String {
+        if as_json {
+            let entries = |list: &[(String, Reason)]| -> String {
+                let entries: Vec<String> = list.iter()
+                    .map(|(name, reason)| json::object(&[("file", json::string(name)),
+                                                         ("reason", json::string(reason.as_str()))]))
+                    .collect();
+                json::array(&entries)
+            };
+            let stack: Vec<String> = self.stack.iter().map(|x| json::string(x)).collect();
+            return format!("{}\n", json::object(&[
+                ("stack", json::array(&stack)),
+                ("unload", entries(&self.unload)),
+                ("load", entries(&self.load))
+            ]));
+        }
+
+        let mut text = String::new();
+        if self.unload.is_empty() && self.load.is_empty() {
+            text.push_str("nothing to do\n");
+        }
+        for (name, reason) in &self.unload {
+            text.push_str(&format!("unload {} ({})\n", name, reason.describe()));
+        }
+        for (name, reason) in &self.load {
+            text.push_str(&format!("load   {} ({})\n", name, reason.describe()));
+        }
+        text
+    }
+}
+
+// Return the shell code with the files to unload and load for a directory. If
+// a log file is given, the decision is recorded in the audit log.
+pub fn list_code(config: &DiscoveryConfig, pwd: &str, loaded: &[String], log: Option<&str>,
+                 pid: &str, cache: Option<&str>, libraries: &mut LibraryCache) -> String {
+    list_plan(config, pwd, loaded, log, pid, cache, libraries).render(config)
+}
+
+// Return the plan that list_code() renders as shell code.
+pub fn list_plan(config: &DiscoveryConfig, pwd: &str, loaded: &[String], log: Option<&str>,
+                 pid: &str, cache: Option<&str>, libraries: &mut LibraryCache) -> Plan {
+    // Take the opportunity to clean up after crashed shells.
+    if let Some(cache) = cache {
+        store::collect_garbage_if_due(cache);
+    }
+
+    let plan = discover_cached(config, pwd, loaded, libraries);
+
+    if let Some(logfile) = log {
+        let mut records: Vec<Record> = vec![];
+        for (name, _) in &plan.unload {
+            records.push(Record::new("unload", name, ""));
+        }
+        for (name, _) in &plan.load {
+            records.push(Record::new("load", name, ""));
+        }
+        audit::append(logfile, pid, &records);
+    }
+
+    plan
+}
+
+// The modification times of the cdenv files for one plan. They are not kept
+// any longer than that, autoreload has to notice a change on the next prompt.
+#[derive(Default)]
+struct Mtimes {
+    files: RefCell<HashMap<String, u64>>
+}
+
+impl Mtimes {
+    fn get(&self, path: &str) -> u64 {
+        *self.files.borrow_mut().entry(path.to_string()).or_insert_with(|| get_mtime(path))
     }
 }
 
-fn list_dirs(context: Context, pwd: &str, loaded: &[String]) -> (Vec<String>, Vec<String>) {
-    let home = env::var("HOME").unwrap_or_else(|_| String::from("/"));
+fn get_mtime(path: &str) -> u64 {
+    match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(time) => time.duration_since(UNIX_EPOCH).map(|age| age.as_secs()).unwrap_or(0),
+        Err(_) => 0
+    }
+}
+
+// Find the cdenv files that are needed for a directory and compare them with
+// the files that are loaded.
+pub fn discover(config: &DiscoveryConfig, pwd: &str, loaded: &[String]) -> Plan {
+    discover_cached(config, pwd, loaded, &mut LibraryCache::default())
+}
+
+// The same as discover() but with the contents of the CDENV_PATH directories
+// taken from a cache.
+pub fn discover_cached(config: &DiscoveryConfig, pwd: &str, loaded: &[String],
+                       libraries: &mut LibraryCache) -> Plan {
+    let home = &config.home;
 
     let mut found: Vec<String> = Vec::new();
 
-    // Collect files from CDENV_PATH.
-    let paths:Vec<_> = context.path.split(':').collect();
+    // Collect files from CDENV_PATH, an empty CDENV_PATH has no directories.
+    let paths:Vec<_> = config.path.split(':').filter(|path| !path.is_empty()).collect();
     for path in paths {
-        for path in glob(format!("{}/*.sh", path).as_str()).unwrap().flatten() {
-            let p = path.display().to_string();
-            found.push(p);
-        }
+        found.append(&mut libraries.list(path));
     }
+    let libraries = found.len();
 
     // Add ~/.cdenv.sh if global is true.
-    if context.global && file_exists(&home, &context.file) {
+    let mut global = None;
+    if config.global && file_exists(home, &config.file) {
         let mut f = home.clone();
         f.push('/');
-        f.push_str(&context.file);
+        f.push_str(&config.file);
+        global = Some(f.clone());
         found.push(f);
     }
 
@@ -76,53 +299,50 @@ fn list_dirs(context: Context, pwd: &str, loaded: &[String]) -> (Vec<String>, Ve
     let mut pwd = pwd.trim_end_matches('/').to_string();
     pwd.push('/');
     for (i, _) in pwd.match_indices('/').collect::<Vec<_>>() {
-        if (!context.global || pwd[..i] != home) && file_exists(&pwd[..i], &context.file) {
+        if (!config.global || pwd[..i] != *home) && file_exists(&pwd[..i], &config.file) {
             let mut f = pwd[..i].to_string();
             f.push('/');
-            f.push_str(&context.file);
+            f.push_str(&config.file);
             found.push(f);
         }
     }
 
-    // Print the new CDENV_STACK value with all found filenames.
-    println!("CDENV_STACK=(");
-    for name in &found {
-        println!("  {:?}", name);
-    }
-    println!(")");
-
     // Compare the list of found filenames with the list of loaded filenames.
-    let mut unload: Vec<String> = Vec::new();
-    let mut load: Vec<String> = Vec::new();
+    let mut plan = Plan {
+        stack: found.clone(),
+        unload: Vec::new(),
+        load: Vec::new(),
+        removed: Vec::new(),
+        changed: Vec::new(),
+        tag: 0
+    };
+
+    let mtimes = Mtimes::default();
+    let changed = |name: &str| config.autoreload && config.tag > 0 && mtimes.get(name) > config.tag;
 
-    if context.reload {
+    if config.reload {
         // If a reload is requested we just unload all loaded and load all found filenames.
         // XXX We could just return reversed(loaded) and found directly.
         for name in &found {
-            load.push(name.to_string());
+            plan.load.push((name.to_string(), Reason::Reload));
         }
         for name in loaded {
-            unload.insert(0, name.to_string());
+            plan.unload.insert(0, (name.to_string(), Reason::Reload));
         }
 
     } else {
-        if context.autoreload {
-            // Print some helpful debug messages about which files changed.
-            println!("removed=(");
+        if config.autoreload {
             for b in loaded {
                 if !Path::new(&b).exists() {
-                    println!("  {:?}", b);
+                    plan.removed.push(b.to_string());
                 }
             }
-            println!(")");
 
-            println!("changed=(");
             for a in &found {
-                if context.tag > 0 && get_mtime(a) > context.tag {
-                    println!("  {:?}", a);
+                if changed(a) {
+                    plan.changed.push(a.to_string());
                 }
             }
-            println!(")");
         }
 
         // Find the point at which the stack of loaded files and the stack of found files diverge.
@@ -134,9 +354,9 @@ fn list_dirs(context: Context, pwd: &str, loaded: &[String]) -> (Vec<String>, Ve
 
         loop {
             if let Some(a) = found.get(i) {
-                if context.autoreload {
-                    let mtime = get_mtime(a);
-                    if context.tag > 0 && mtime > context.tag {
+                if config.autoreload {
+                    let mtime = mtimes.get(a);
+                    if config.tag > 0 && mtime > config.tag {
                         // The file has been changed in the meantime.
                         break;
                     }
@@ -157,14 +377,34 @@ fn list_dirs(context: Context, pwd: &str, loaded: &[String]) -> (Vec<String>, Ve
         }
 
         while let Some(b) = loaded.get(j) {
-            unload.insert(0, b.to_string());
+            let reason = if !Path::new(&b).exists() {
+                Reason::Removed
+            } else if changed(b) {
+                Reason::Changed
+            } else if found.contains(b) {
+                Reason::Diverged
+            } else {
+                Reason::Left
+            };
+            plan.unload.insert(0, (b.to_string(), reason));
             j += 1
         }
 
         while let Some(a) = found.get(i) {
-            load.push(a.to_string());
-            if context.autoreload {
-                let mtime = get_mtime(a);
+            let reason = if loaded.contains(a) && changed(a) {
+                Reason::Changed
+            } else if loaded.contains(a) {
+                Reason::Diverged
+            } else if i < libraries {
+                Reason::Library
+            } else if Some(a) == global.as_ref() {
+                Reason::Global
+            } else {
+                Reason::Entered
+            };
+            plan.load.push((a.to_string(), reason));
+            if config.autoreload {
+                let mtime = mtimes.get(a);
                 if mtime > new_tag {
                     new_tag = mtime;
                 }
@@ -172,12 +412,10 @@ fn list_dirs(context: Context, pwd: &str, loaded: &[String]) -> (Vec<String>, Ve
             i += 1;
         }
 
-        if context.autoreload {
-            println!("CDENV_TAG={}", new_tag);
-        }
+        plan.tag = new_tag;
     }
 
-    (unload, load)
+    plan
 }
 
 fn file_exists(path: &str, file: &str) -> bool {
@@ -0,0 +1,121 @@
+// cdenv - fish.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Snapshots from fish and the restore statements for it. fish has no output
+// format that can be read back for all of its variables, functions and
+// abbreviations at once, so c:snapshot in cdenv.fish prints one line for each
+// of them with the names and values percent-encoded by string escape
+// --style=url:
+//
+//   var <name> -g|-gx [=<value> ...]
+//   func <name> <definition>
+//   abbr <name> <abbr --show line>
+//
+// Only global variables are part of a snapshot. Universal variables are meant
+// to outlive the shell and are left alone.
+
+use std::io::BufRead;
+
+use environment::{parse_state, Dialect, Snapshot};
+use manifest::Kind;
+use store::fnv1a;
+
+pub fn parse_environment(reader: &mut dyn BufRead, snapshot: &mut Snapshot) {
+    let mut in_state = false;
+
+    for line in reader.split(b'\n') {
+        let line = String::from_utf8_lossy(&line.unwrap()).to_string();
+
+        if let Some(dialect) = Dialect::parse(&line) {
+            snapshot.dialect = dialect;
+            continue;
+        } else if line == "# cdenv state" {
+            in_state = true;
+            continue;
+        } else if in_state {
+            parse_state(&line, snapshot);
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields.as_slice() {
+            ["var", name, flags @ ("-g" | "-gx"), values @ ..] if values.iter().all(|v| v.starts_with('=')) => {
+                let mut statement = format!("set {} {}", flags, name);
+                for value in values {
+                    statement.push(' ');
+                    statement.push_str(&quote(&decode(&value[1..])));
+                }
+                statement.push('\n');
+                snapshot.vars.insert(name.to_string(), statement);
+            },
+            ["func", name, definition] => {
+                snapshot.funcs.insert(decode(name), format!("{}\n", decode(definition)));
+            },
+            ["abbr", name, definition] => {
+                snapshot.aliases.insert(decode(name), format!("{}\n", decode(definition)));
+            },
+            _ => snapshot.unparsed.push(line)
+        }
+    }
+}
+
+// Decode a percent-encoded string. Invalid escapes are kept as they are.
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                result.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                result.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).to_string()
+}
+
+// Quote a string for fish. Inside single quotes fish only knows the escapes
+// \' and \\.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+// Return the statement that removes a name.
+pub fn unset_statement(kind: Kind, key: &str) -> String {
+    match kind {
+        Kind::Function => format!("functions -e -- {}\n", quote(key)),
+        Kind::Alias => format!("abbr -e -- {}\n", quote(key)),
+        _ => format!("set -e -g {}\n", key)
+    }
+}
+
+// Keep a restore statement in a global variable instead of the restore file,
+// see restore_statement() in environment.rs. fish has no associative arrays,
+// so there is one variable for each statement, named after a hash of index.
+pub fn redact_statement(index: &str, statement: &str, output: &mut String) -> String {
+    let name = format!("__cdenv_redacted_{:016x}", fnv1a(index.as_bytes()));
+    output.push_str(&format!("set -g {} {}\n", name, quote(statement)));
+    format!("eval ${}\nset -e -g {}\n", name, name)
+}
@@ -0,0 +1,65 @@
+// cdenv - lib.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// The cdenv library. Snapshot::parse() and diff() compare two shell
+// environments, discover() finds the cdenv files for a directory, and
+// Diff::render() and Plan::render() turn the results into shell code. The
+// cdenv executable is a thin command line layer on top of these. Functions of
+// the library return their results and errors, I/O errors of the restore store
+// included, and leave printing them and exiting to the caller.
+
+extern crate regex;
+extern crate glob;
+
+pub mod audit;
+mod batch;
+pub mod blame;
+pub mod drift;
+pub mod environment;
+pub mod exec;
+pub mod export;
+pub mod file;
+pub mod fish;
+pub mod json;
+pub mod lint;
+pub mod locate;
+pub mod manifest;
+pub mod quoting;
+pub mod serve;
+pub mod sh;
+pub mod status;
+pub mod store;
+pub mod verify;
+pub mod zsh;
+
+pub use environment::{diff, Definition, Diff, Rendered, Snapshot};
+pub use file::{discover, DiscoveryConfig, Plan, Reason};
+pub use manifest::{Action, Change, Kind, Value};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// The options of the compare command, which records the changes of a single
+// cdenv file.
+pub struct CompareContext {
+    pub store: store::Store,
+    pub file: String,
+    pub redact: Option<String>,
+    pub log: Option<String>,
+    pub pid: String,
+    pub loaded: Vec<String>,
+    pub shadow: u32
+}
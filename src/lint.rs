@@ -0,0 +1,420 @@
+// cdenv - lint.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::fs;
+
+use glob::glob;
+use regex::Regex;
+
+use json;
+
+// The helper functions that are defined in cdenv.sh itself.
+const BUILTIN_HELPERS: &[&str] = &["c.err", "c.warn", "c.msg", "c.debug", "c.translate"];
+
+// The libraries that come with cdenv. Their helpers are known even if lint
+// runs without --path, e.g. from an editor. Other libraries are collected from
+// CDENV_PATH.
+const STDLIB: &[&str] = &[include_str!("../libs/01_stdlib.sh"), include_str!("../libs/02_variables.sh")];
+
+// Words that may precede the actual command in a simple command.
+const PREFIX_WORDS: &[&str] = &["if", "then", "else", "elif", "do", "while", "until", "!", "time",
+                                "command", "builtin", "{", "("];
+
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub severity: &'static str,
+    pub code: &'static str,
+    pub message: String
+}
+
+// The state of the lexer that is carried from one line to the next.
+#[derive(Default)]
+struct LexerState {
+    in_single: bool,
+    in_double: bool,
+    heredoc: Option<(String, bool)>,
+    braces: i32,
+    parens: i32,
+    functions: Vec<i32>
+}
+
+// Check all files and return the diagnostics.
+pub fn lint_files(files: &[String], path: &str) -> Vec<Diagnostic> {
+    let helpers = collect_helpers(path);
+    let mut diagnostics = vec![];
+
+    for file in files {
+        match fs::read_to_string(file) {
+            Ok(text) => diagnostics.append(&mut lint(file, &text, &helpers)),
+            Err(err) => diagnostics.push(Diagnostic {
+                file: file.to_string(),
+                line: 0,
+                column: 0,
+                severity: "error",
+                code: "unreadable",
+                message: err.to_string()
+            })
+        }
+    }
+
+    diagnostics
+}
+
+// Format the diagnostics either as JSON or one per line, like compilers do.
+pub fn format_diagnostics(diagnostics: &[Diagnostic], as_json: bool) -> String {
+    if as_json {
+        let items: Vec<String> = diagnostics.iter().map(|d| json::object(&[
+            ("file", json::string(&d.file)),
+            ("line", d.line.to_string()),
+            ("column", d.column.to_string()),
+            ("severity", json::string(d.severity)),
+            ("code", json::string(d.code)),
+            ("message", json::string(&d.message))
+        ])).collect();
+        format!("{}\n", json::array(&items))
+    } else {
+        diagnostics.iter()
+            .map(|d| format!("{}:{}:{}: {}: {} [{}]\n", d.file, d.line, d.column, d.severity, d.message,
+                             d.code))
+            .collect()
+    }
+}
+
+// Collect the names of all c.* helper functions from cdenv.sh, the stdlib and
+// the files in CDENV_PATH.
+fn collect_helpers(path: &str) -> HashSet<String> {
+    let mut helpers: HashSet<String> = BUILTIN_HELPERS.iter().map(|x| x.to_string()).collect();
+    for text in STDLIB {
+        helpers.extend(defined_functions(text));
+    }
+    for dir in path.split(':').filter(|x| !x.is_empty()) {
+        for file in glob(format!("{}/*.sh", dir).as_str()).unwrap().flatten() {
+            if let Ok(text) = fs::read_to_string(file) {
+                helpers.extend(defined_functions(&text));
+            }
+        }
+    }
+    helpers
+}
+
+fn defined_functions(text: &str) -> Vec<String> {
+    let re = Regex::new(r"^\s*(function\s+)?([^\s()=]+)\s*(\(\s*\)|\{)").unwrap();
+    text.lines()
+        .filter_map(|line| re.captures(line))
+        .filter(|groups| groups.get(1).is_some() || groups.get(3).unwrap().as_str() != "{")
+        .map(|groups| groups.get(2).unwrap().as_str().to_string())
+        .collect()
+}
+
+fn lint(file: &str, text: &str, helpers: &HashSet<String>) -> Vec<Diagnostic> {
+    let re_function = Regex::new(r"^\s*(function\s+\S+|\S+\s*\(\s*\))\s*(\{|$)").unwrap();
+    let re_heredoc = Regex::new(r#"<<-?\s*['"]?([A-Za-z_][A-Za-z0-9_]*)['"]?"#).unwrap();
+    let re_background = Regex::new(r"(^|[^&>|<0-9])&([^&>]|$)").unwrap();
+    let re_redirect = Regex::new(r"(^|[^<>&0-9])[0-9]?>>?\s*([^\s&;|>]+)").unwrap();
+
+    let local: HashSet<String> = defined_functions(text).into_iter().collect();
+    let mut diagnostics = vec![];
+    let mut state = LexerState::default();
+
+    for (i, line) in text.lines().enumerate() {
+        let lineno = i + 1;
+
+        // Skip the contents of here-documents.
+        if let Some((delimiter, strip)) = state.heredoc.clone() {
+            let end = if strip { line.trim_start_matches('\t') } else { line };
+            if end == delimiter {
+                state.heredoc = None;
+            }
+            continue;
+        }
+
+        let masked = mask(line, &mut state);
+
+        let mut add = |column: usize, severity: &'static str, code: &'static str, message: String| {
+            diagnostics.push(Diagnostic {
+                file: file.to_string(),
+                line: lineno,
+                column: column + 1,
+                severity,
+                code,
+                message
+            });
+        };
+
+        if re_function.is_match(&masked) {
+            state.functions.push(state.braces);
+        }
+        let toplevel = state.functions.is_empty();
+
+        for (column, depth, words) in commands(&masked, state.parens) {
+            let command = match words.first() {
+                Some(command) => command.as_str(),
+                None => continue
+            };
+            let args: Vec<&str> = words[1..].iter().map(|x| x.as_str()).collect();
+
+            if command.starts_with("c.") && !helpers.contains(command) && !local.contains(command) {
+                add(column, "error", "unknown-helper",
+                    format!("{} is not a cdenv helper function", command));
+            }
+
+            if !toplevel || depth > 0 {
+                continue;
+            }
+
+            match command {
+                "cd" | "pushd" | "popd" => {
+                    add(column, "warning", "cd",
+                        format!("{} changes the working directory, use a subshell or a function instead", command));
+                },
+                "exit" => {
+                    add(column, "error", "exit",
+                        String::from("exit terminates the interactive shell, use return instead"));
+                },
+                "exec" if args.first().map(|a| !a.starts_with(|c: char| c.is_ascii_digit() || c == '{' || c == '<' || c == '>')).unwrap_or(false) => {
+                    add(column, "error", "exec", String::from("exec replaces the interactive shell"));
+                },
+                "set" if args.iter().any(|a| is_set_option(a, args.as_slice())) => {
+                    add(column, "warning", "set-option",
+                        String::from("set -e and set -u leak into the interactive shell and may break the prompt"));
+                },
+                "trap" if args.iter().skip(1).any(|a| *a == "EXIT" || *a == "0" || *a == "SIGEXIT") => {
+                    add(column, "warning", "trap-exit",
+                        String::from("trap on EXIT replaces the handler that cdenv uses to clean up, use c.exit_callback_add instead"));
+                },
+                "source" | "." if args.first().map(|a| is_relative(a)).unwrap_or(false) => {
+                    add(column, "warning", "relative-source",
+                        format!("{} of the relative path {} depends on the working directory", command, args[0]));
+                },
+                "tee" => {
+                    add(column, "warning", "file-write",
+                        String::from("writes to files cannot be undone when the directory is left"));
+                },
+                _ => {}
+            }
+        }
+
+        if toplevel && state.parens == 0 && !masked.contains('(') {
+            if let Some(m) = re_background.find(&masked) {
+                add(m.start(), "warning", "background-job",
+                    String::from("background jobs keep running after the directory is left"));
+            }
+            for groups in re_redirect.captures_iter(&masked) {
+                let target = groups.get(2).unwrap();
+                if !target.as_str().starts_with("/dev/") {
+                    add(target.start(), "warning", "file-write",
+                        String::from("writes to files cannot be undone when the directory is left"));
+                }
+            }
+        }
+
+        if let Some(groups) = re_heredoc.captures(line) {
+            if masked[groups.get(0).unwrap().start()..].starts_with("<<") {
+                let strip = groups.get(0).unwrap().as_str().starts_with("<<-");
+                state.heredoc = Some((groups.get(1).unwrap().as_str().to_string(), strip));
+            }
+        }
+
+        // Leave the function body when its closing brace is reached.
+        for c in masked.chars() {
+            match c {
+                '{' => state.braces += 1,
+                '}' => {
+                    state.braces -= 1;
+                    if state.functions.last() == Some(&state.braces) {
+                        state.functions.pop();
+                    }
+                },
+                '(' => state.parens += 1,
+                ')' => state.parens = (state.parens - 1).max(0),
+                _ => {}
+            }
+        }
+    }
+
+    diagnostics
+}
+
+// Replace quoted text and comments with spaces, so that the line can be split
+// into words without being confused by special characters in strings. The
+// positions of the remaining characters are preserved.
+fn mask(line: &str, state: &mut LexerState) -> String {
+    let mut result = String::new();
+    let mut escaped = false;
+    let mut previous = ' ';
+
+    for c in line.chars() {
+        if state.in_single {
+            if c == '\'' {
+                state.in_single = false;
+                result.push('\'');
+            } else {
+                result.push(' ');
+            }
+        } else if state.in_double {
+            if escaped {
+                escaped = false;
+                result.push(' ');
+            } else if c == '\\' {
+                escaped = true;
+                result.push(' ');
+            } else if c == '"' {
+                state.in_double = false;
+                result.push('"');
+            } else {
+                result.push(' ');
+            }
+        } else if escaped {
+            escaped = false;
+            result.push(' ');
+        } else if c == '\\' {
+            escaped = true;
+            result.push(' ');
+        } else if c == '\'' {
+            state.in_single = true;
+            result.push('\'');
+        } else if c == '"' {
+            state.in_double = true;
+            result.push('"');
+        } else if c == '#' && (previous.is_whitespace() || ";&|(".contains(previous)) {
+            break;
+        } else {
+            result.push(c);
+        }
+        previous = c;
+    }
+
+    result
+}
+
+// Split a masked line into simple commands and return the column of each
+// command, the depth of subshells it runs in and its words, without leading
+// keywords and assignments.
+fn commands(masked: &str, parens: i32) -> Vec<(usize, i32, Vec<String>)> {
+    let mut result = vec![];
+    let mut start = 0;
+    let mut depth = parens;
+    let chars: Vec<char> = masked.chars().collect();
+
+    let mut i = 0;
+    while i <= chars.len() {
+        let end = i == chars.len();
+        let separator = !end && matches!(chars[i], ';' | '&' | '|' | '(' | ')' | '`');
+        if end || separator {
+            let segment: String = chars[start..i].iter().collect();
+            let mut column = start;
+            let mut words = vec![];
+            let mut offset = 0;
+            for word in segment.split_whitespace() {
+                let position = segment[offset..].find(word).unwrap() + offset;
+                offset = position + word.len();
+                if words.is_empty() && (PREFIX_WORDS.contains(&word) || is_assignment(word)) {
+                    continue;
+                }
+                if words.is_empty() {
+                    column = start + position;
+                }
+                words.push(word.trim_matches(|c| c == '\'' || c == '"').to_string());
+            }
+            result.push((column, depth, words));
+            start = i + 1;
+            match chars.get(i) {
+                Some('(') => depth += 1,
+                Some(')') => depth = (depth - 1).max(0),
+                _ => {}
+            }
+        }
+        i += 1;
+    }
+
+    result
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.find('=') {
+        Some(i) => i > 0 && word[..i].trim_end_matches('+').chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '[' || c == ']'),
+        None => false
+    }
+}
+
+fn is_set_option(arg: &str, args: &[&str]) -> bool {
+    if arg.starts_with('-') && !arg.starts_with("--") && arg != "-o" {
+        return arg.contains('e') || arg.contains('u');
+    }
+    (arg == "errexit" || arg == "nounset") && args.contains(&"-o")
+}
+
+fn is_relative(path: &str) -> bool {
+    !path.is_empty() && !path.starts_with('/') && !path.starts_with('~') && !path.starts_with('$')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Return the line and code of each diagnostic.
+    fn check(text: &str) -> Vec<(usize, &'static str)> {
+        let helpers = BUILTIN_HELPERS.iter().map(|x| x.to_string()).collect();
+        lint("test.sh", text, &helpers).into_iter().map(|d| (d.line, d.code)).collect()
+    }
+
+    #[test]
+    fn toplevel_commands() {
+        assert_eq!(check("cd /tmp\nexit 1\nexec vim\nset -eu\ntrap 'x' EXIT\nsource lib.sh\n"),
+                   vec![(1, "cd"), (2, "exit"), (3, "exec"), (4, "set-option"), (5, "trap-exit"),
+                        (6, "relative-source")]);
+        assert_eq!(check("sleep 10 &\necho x > out.txt\necho x | tee log\n"),
+                   vec![(1, "background-job"), (2, "file-write"), (3, "file-write")]);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(check("echo 'cd /tmp; exit'\necho \"a > b & c\"\necho \\; exit\n"), vec![]);
+        assert_eq!(check("echo 'one\ncd /tmp\n'\ncd /tmp\n"), vec![(4, "cd")]);
+        assert_eq!(check("echo x # > out.txt; exit\n"), vec![]);
+    }
+
+    #[test]
+    fn heredocs() {
+        assert_eq!(check("cat <<EOF\nexit\ncd /tmp\nEOF\nexit\n"), vec![(5, "exit")]);
+        assert_eq!(check("cat <<-'END'\n\texit\n\tEND\ncd /tmp\n"), vec![(4, "cd")]);
+    }
+
+    #[test]
+    fn helpers() {
+        assert_eq!(check("c.msg hello\nc.mesg hello\n"), vec![(2, "unknown-helper")]);
+        assert_eq!(check("c.local() { :; }\nc.local\n"), vec![]);
+        assert_eq!(defined_functions("foo() {\nfunction bar {\nbaz {\n"), vec!["foo", "bar"]);
+        let helpers = collect_helpers("");
+        assert!(helpers.contains("c.leave") && helpers.contains("c.var_prepend"));
+    }
+
+    // Things that are fine in a cdenv file.
+    #[test]
+    fn false_positives() {
+        assert_eq!(check("up() {\n    cd ..\n    exit 1\n}\n"), vec![]);
+        assert_eq!(check("(cd /tmp && make)\n"), vec![]);
+        assert_eq!(check("exec 3>&1\nexec >/dev/null\n"), vec![]);
+        assert_eq!(check("set -o vi\nset +x\ntrap 'x' INT\n"), vec![]);
+        assert_eq!(check("source /etc/profile\n. \"$HOME/.env\"\n"), vec![]);
+        assert_eq!(check("true && false || true\necho x 2>&1 >/dev/null\nFOO=$(date)\n"), vec![]);
+    }
+}
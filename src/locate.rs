@@ -0,0 +1,134 @@
+// cdenv - locate.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use regex::{self, Regex};
+
+use manifest::{Action, Change, Kind, Location};
+use store::Store;
+
+// Find the locations of the names a cdenv file added or modified. The
+// locations of functions are known exactly from declare -F, for variables and
+// aliases we search the cdenv file for the most likely assignment.
+pub fn find_locations(file: &str, changes: &[Change],
+                      functions: &HashMap<String, (String, usize)>) -> Vec<Location> {
+    let lines: Vec<String> = match fs::read_to_string(file) {
+        Ok(text) => text.lines().map(|x| x.to_string()).collect(),
+        Err(_) => vec![]
+    };
+
+    let mut locations = vec![];
+
+    for change in changes.iter().filter(|c| c.action != Action::Remove) {
+        let pattern = match change.kind {
+            Kind::Function => function_pattern(&change.name),
+            Kind::Variable => variable_pattern(&change.name),
+            Kind::Alias => alias_pattern(&change.name),
+            Kind::Option => option_pattern(&change.name)
+        };
+
+        // The cdenv file is sourced from its own directory, so relative paths
+        // from declare -F are relative to it. For functions that were not
+        // defined in a file, declare -F reports "main" or "environment".
+        let directory = Path::new(file).parent().unwrap_or_else(|| Path::new("/"));
+        let location = match functions.get(&change.name) {
+            Some((path, line)) if change.kind == Kind::Function => {
+                Some((directory.join(path.trim_start_matches("./")), *line))
+            },
+            _ => None
+        };
+
+        let location = match location {
+            Some((path, line)) if path.exists() => Some((path.display().to_string(), line)),
+            _ => search(&lines, &pattern).map(|line| (file.to_string(), line))
+        };
+
+        if let Some((path, line)) = location {
+            locations.push(Location {
+                kind: change.kind,
+                name: change.name.clone(),
+                file: path,
+                line
+            });
+        }
+    }
+
+    locations
+}
+
+// Return the number of the first line that matches the pattern and is not a
+// comment.
+fn search(lines: &[String], pattern: &str) -> Option<usize> {
+    let regex = Regex::new(pattern).unwrap();
+    lines.iter()
+        .position(|line| !line.trim_start().starts_with('#') && regex.is_match(line))
+        .map(|i| i + 1)
+}
+
+// The start of a simple command, i.e. the start of the line or a command separator.
+const COMMAND_START: &str = r"(^|[;&|({]\s*|\b(then|do|else)\s+)\s*";
+
+fn function_pattern(name: &str) -> String {
+    let name = regex::escape(name);
+    format!(r"{}(function\s+{}\b|{}\s*\(\s*\))", COMMAND_START, name, name)
+}
+
+fn variable_pattern(name: &str) -> String {
+    let name = regex::escape(name);
+    format!(r"{}((export|declare|typeset|readonly|local)(\s+-\w+)*\s+([^;&|]*\s)?{n}\b|{n}(\[[^]]*\])?\+?=|c\.(set)?var_(prepend|append|remove)\s+{n}\b)",
+            COMMAND_START, n=name)
+}
+
+fn alias_pattern(name: &str) -> String {
+    format!(r"{}alias\s+([^;&|]*\s)?{}=", COMMAND_START, regex::escape(name))
+}
+
+fn option_pattern(name: &str) -> String {
+    format!(r"{}(shopt|set)\s+[^;&|]*\b{}\b", COMMAND_START, regex::escape(name))
+}
+
+// Return the location of the most recent definition of a name as file:line. If
+// layer is true, return the cdenv file that made the definition instead. It is
+// an error if no cdenv file changed the name or the definition cannot be
+// found in the file that did.
+pub fn locate(store: &Store, stack: &[String], name: &str, layer: bool) -> Result<String, String> {
+    let name = name.trim_end_matches("()").trim_end_matches('*');
+
+    for file in stack.iter().rev() {
+        let manifest = match store.read_manifest(file) {
+            Some(manifest) => manifest,
+            None => continue
+        };
+
+        if !manifest.changes.iter().any(|c| c.name == name && c.action != Action::Remove) {
+            continue;
+        }
+
+        if layer {
+            return Ok(file.to_string());
+        }
+        return match manifest.locations.iter().find(|l| l.name == name) {
+            Some(location) => Ok(format!("{}:{}", location.file, location.line)),
+            None => Err(format!("definition of {} not found in {}", name, file))
+        };
+    }
+
+    Err(format!("no such variable / function / alias: {}", name))
+}
@@ -15,29 +15,25 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-extern crate regex;
+extern crate cdenv;
 extern crate clap;
-extern crate glob;
 
 use std::env;
-use clap::{App, Arg, SubCommand};
+use std::fs::File;
+use std::io;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, PathBuf};
+use std::process;
+use clap::{App, Arg, ArgMatches, SubCommand};
 
-mod environment;
-mod file;
+use cdenv::{audit, blame, drift, environment, exec, export, file, lint, locate, serve, status,
+            store, verify};
+use cdenv::{CompareContext, DiscoveryConfig, VERSION};
+use cdenv::environment::Shell;
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
-
-pub struct Context {
-    global: bool,
-    reload: bool,
-    autoreload: bool,
-    tag: u64,
-    file: String,
-    path: String
-}
-
-fn main() {
-    let matches = App::new("cdenv")
+fn app() -> App<'static, 'static> {
+    App::new("cdenv")
                     .subcommand(SubCommand::with_name("list")
                                 .arg(Arg::with_name("global")
                                      .long("--global")
@@ -45,7 +41,8 @@ fn main() {
                                      .required(true))
                                 .arg(Arg::with_name("file")
                                      .long("--file")
-                                     .takes_value(true))
+                                     .takes_value(true)
+                                     .default_value(".cdenv.sh"))
                                 .arg(Arg::with_name("path")
                                      .long("--path")
                                      .takes_value(true)
@@ -53,11 +50,55 @@ fn main() {
                                 .arg(Arg::with_name("tag")
                                      .long("--tag")
                                      .takes_value(true)
+                                     .required(true)
+                                     .validator(number_value))
+                                .arg(Arg::with_name("reload")
+                                     .long("--reload"))
+                                .arg(Arg::with_name("autoreload")
+                                     .long("--autoreload"))
+                                .arg(Arg::with_name("shell")
+                                     .long("--shell")
+                                     .takes_value(true)
+                                     .possible_values(&["bash", "zsh", "fish", "sh"]))
+                                .arg(Arg::with_name("log")
+                                     .long("--log")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("pid")
+                                     .long("--pid")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("cache")
+                                     .long("--cache")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("pwd")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("loaded")
+                                     .takes_value(true)
+                                     .multiple(true)))
+                    .subcommand(SubCommand::with_name("plan")
+                                .arg(Arg::with_name("global")
+                                     .long("--global")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("file")
+                                     .long("--file")
+                                     .takes_value(true)
+                                     .default_value(".cdenv.sh"))
+                                .arg(Arg::with_name("path")
+                                     .long("--path")
+                                     .takes_value(true)
                                      .required(true))
+                                .arg(Arg::with_name("tag")
+                                     .long("--tag")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(number_value))
                                 .arg(Arg::with_name("reload")
                                      .long("--reload"))
                                 .arg(Arg::with_name("autoreload")
                                      .long("--autoreload"))
+                                .arg(Arg::with_name("json")
+                                     .long("--json"))
                                 .arg(Arg::with_name("pwd")
                                      .takes_value(true)
                                      .required(true))
@@ -65,49 +106,510 @@ fn main() {
                                      .takes_value(true)
                                      .multiple(true)))
                     .subcommand(SubCommand::with_name("compare")
+                                .arg(Arg::with_name("log")
+                                     .long("--log")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("pid")
+                                     .long("--pid")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("store")
+                                     .long("--store")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("file")
+                                     .long("--file")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("redact")
+                                     .long("--redact")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("shadow")
+                                     .long("--shadow")
+                                     .takes_value(true)
+                                     .validator(number_value))
+                                .arg(Arg::with_name("input")
+                                     .long("--input")
+                                     .takes_value(true))
                                 .arg(Arg::with_name("path")
                                      .takes_value(true)
                                      .required(true))
-                                .arg(Arg::with_name("restore")
+                                .arg(Arg::with_name("loaded")
+                                     .takes_value(true)
+                                     .multiple(true)))
+                    .subcommand(SubCommand::with_name("store")
+                                .arg(Arg::with_name("dir")
+                                     .long("--dir")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("command")
+                                     .possible_values(&["path", "write", "read", "remove"])
+                                     .required(true))
+                                .arg(Arg::with_name("file")
+                                     .takes_value(true)
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("gc")
+                                .arg(Arg::with_name("cache")
+                                     .long("--cache")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("pid")
+                                     .long("--pid")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("log")
+                                .arg(Arg::with_name("log")
+                                     .long("--log")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("file")
+                                     .long("--file")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("name")
+                                     .long("--name")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("pid")
+                                     .long("--pid")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("since")
+                                     .long("--since")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("until")
+                                     .long("--until")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("status")
+                                .arg(Arg::with_name("store")
+                                     .long("--store")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("verbose")
+                                     .long("--verbose")
+                                     .short("v"))
+                                .arg(Arg::with_name("json")
+                                     .long("--json"))
+                                .arg(Arg::with_name("loaded")
+                                     .takes_value(true)
+                                     .multiple(true)))
+                    .subcommand(SubCommand::with_name("blame")
+                                .arg(Arg::with_name("store")
+                                     .long("--store")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("loaded")
+                                     .takes_value(true)
+                                     .multiple(true)))
+                    .subcommand(SubCommand::with_name("why")
+                                .arg(Arg::with_name("store")
+                                     .long("--store")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("name")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("loaded")
+                                     .takes_value(true)
+                                     .multiple(true)))
+                    .subcommand(SubCommand::with_name("baseline")
+                                .arg(Arg::with_name("store")
+                                     .long("--store")
                                      .takes_value(true)
                                      .required(true)))
+                    .subcommand(SubCommand::with_name("drift")
+                                .arg(Arg::with_name("store")
+                                     .long("--store")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("loaded")
+                                     .takes_value(true)
+                                     .multiple(true)))
+                    .subcommand(SubCommand::with_name("locate")
+                                .arg(Arg::with_name("store")
+                                     .long("--store")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("layer")
+                                     .long("--layer"))
+                                .arg(Arg::with_name("name")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("loaded")
+                                     .takes_value(true)
+                                     .multiple(true)))
+                    .subcommand(SubCommand::with_name("lint")
+                                .arg(Arg::with_name("path")
+                                     .long("--path")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("json")
+                                     .long("--json"))
+                                .arg(Arg::with_name("file")
+                                     .takes_value(true)
+                                     .multiple(true)
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("verify")
+                                .arg(Arg::with_name("sh")
+                                     .long("--sh")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("dir")
+                                     .takes_value(true)
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("exec")
+                                .arg(Arg::with_name("sh")
+                                     .long("--sh")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("dir")
+                                     .takes_value(true)
+                                     .required(true))
+                                .arg(Arg::with_name("command")
+                                     .takes_value(true)
+                                     .multiple(true)
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("export")
+                                .arg(Arg::with_name("sh")
+                                     .long("--sh")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("format")
+                                     .long("--format")
+                                     .takes_value(true)
+                                     .possible_values(export::FORMATS)
+                                     .default_value("dotenv"))
+                                .arg(Arg::with_name("dir")
+                                     .takes_value(true)
+                                     .required(true)))
+                    .subcommand(SubCommand::with_name("serve"))
                     .subcommand(SubCommand::with_name("version"))
-                    .get_matches();
+}
+
+fn main() {
+    let matches = app().get_matches();
 
     if let Some(matches) = matches.subcommand_matches("list") {
-        let tag_str = matches.value_of("tag").unwrap();
-        let context = Context {
-            global: match matches.value_of("global").unwrap() {
-                "0" => false,
-                "1" => true,
-                _ => false // simply default to false
-            },
-            reload: matches.is_present("reload"),
-            autoreload: matches.is_present("autoreload"),
-            tag: match tag_str.parse() {
-                Ok(number) => { number },
-                Err(_) => panic!("invalid number {:?}", tag_str),
-            },
-            file: matches.value_of("file").unwrap().to_string(),
-            path: matches.value_of("path").unwrap().to_string(),
+        let pwd = matches.value_of("pwd").unwrap();
+        let loaded = values_option(matches, "loaded");
+
+        print!("{}", file::list_code(&list_config(matches), pwd, &loaded, log_option(matches).as_deref(),
+                                     matches.value_of("pid").unwrap_or("0"), matches.value_of("cache"),
+                                     &mut file::LibraryCache::default()));
+
+    } else if let Some(matches) = matches.subcommand_matches("plan") {
+        let pwd = absolute_path(matches.value_of("pwd").unwrap());
+        let loaded = values_option(matches, "loaded");
+
+        print!("{}", cdenv::discover(&list_config(matches), &pwd, &loaded).describe(matches.is_present("json")));
+
+    } else if let Some(matches) = matches.subcommand_matches("compare") {
+        match environment::compare_environments(compare_context(matches), matches.value_of("path").unwrap(),
+                                                matches.value_of("input")) {
+            Ok(code) => print!("{}", code),
+            Err(err) => fail(&err.to_string(), 2)
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("store") {
+        if let Err(err) = store_command(matches.value_of("dir").unwrap(),
+                                        matches.value_of("command").unwrap(),
+                                        matches.value_of("file").unwrap()) {
+            fail(&format!("unable to access the restore file: {}", err), 1);
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("gc") {
+        let cache = matches.value_of("cache").unwrap();
+        store::collect_garbage(cache);
+        if let Some(pid) = matches.value_of("pid") {
+            if let Err(err) = store::claim(cache, pid) {
+                fail(&format!("unable to create the restore directory: {}", err), 1);
+            }
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("log") {
+        let filter = audit::Filter {
+            file: matches.value_of("file").map(absolute_path),
+            name: matches.value_of("name").map(|x| x.to_string()),
+            pid: matches.value_of("pid").map(|x| x.to_string()),
+            since: time_option(matches, "since"),
+            until: time_option(matches, "until"),
         };
+        for line in audit::read_log(matches.value_of("log").unwrap(), &filter) {
+            println!("{}", line);
+        }
 
-        let pwd = matches.value_of("pwd").unwrap();
-        let loaded: Vec<String>;
-        if matches.is_present("loaded") {
-            loaded = matches.values_of("loaded").unwrap().map(|x| x.to_string()).collect();
-        } else {
-            loaded = vec![];
+    } else if let Some(matches) = matches.subcommand_matches("status") {
+        let store = store::Store::new(matches.value_of("store").unwrap());
+        let loaded = values_option(matches, "loaded");
+        print!("{}", status::status(&store, &loaded, matches.is_present("verbose"),
+                                    matches.is_present("json")));
+
+    } else if let Some(matches) = matches.subcommand_matches("blame") {
+        let store = store::Store::new(matches.value_of("store").unwrap());
+        let loaded = values_option(matches, "loaded");
+        print!("{}", blame::blame(&store, &loaded, &read_snapshot()));
+
+    } else if let Some(matches) = matches.subcommand_matches("why") {
+        let store = store::Store::new(matches.value_of("store").unwrap());
+        let loaded = values_option(matches, "loaded");
+        let name = matches.value_of("name").unwrap();
+        match blame::why(&store, &loaded, &read_snapshot(), name) {
+            Some(history) => print!("{}", history),
+            None => fail(&format!("{} has not been changed by any loaded cdenv file", name), 1)
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("baseline") {
+        let store = store::Store::new(matches.value_of("store").unwrap());
+        if let Err(err) = drift::write_baseline(&store, &read_snapshot()) {
+            fail(&format!("unable to write {}: {}", store.baseline_path().display(), err), 1);
         }
 
-        file::list_paths(context, pwd, &loaded);
+    } else if let Some(matches) = matches.subcommand_matches("drift") {
+        let store = store::Store::new(matches.value_of("store").unwrap());
+        let loaded = values_option(matches, "loaded");
+        match drift::drift(&store, &loaded, &read_snapshot()) {
+            Ok(drift) => print!("{}", drift),
+            Err(err) => eprintln!("{}", err)
+        }
 
-    } else if let Some(matches) = matches.subcommand_matches("compare") {
-        let input = matches.value_of("path").unwrap();
-        let restore = matches.value_of("restore").unwrap();
-        environment::compare_environments(input, restore);
+    } else if let Some(matches) = matches.subcommand_matches("locate") {
+        let store = store::Store::new(matches.value_of("store").unwrap());
+        let loaded = values_option(matches, "loaded");
+        match locate::locate(&store, &loaded, matches.value_of("name").unwrap(),
+                             matches.is_present("layer")) {
+            Ok(location) => println!("{}", location),
+            Err(err) => fail(&err, 1)
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("lint") {
+        let files = values_option(matches, "file");
+        let diagnostics = lint::lint_files(&files, matches.value_of("path").unwrap_or(""));
+        print!("{}", lint::format_diagnostics(&diagnostics, matches.is_present("json")));
+        if !diagnostics.is_empty() {
+            process::exit(1);
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("verify") {
+        let dir = absolute_path(matches.value_of("dir").unwrap());
+        match verify::verify(matches.value_of("sh"), &dir) {
+            Ok(leaks) if leaks.is_empty() => println!("{}: ok", dir),
+            Ok(leaks) => {
+                for leak in leaks {
+                    println!("leak: {}", leak);
+                }
+                process::exit(1);
+            },
+            Err(err) => fail(&err, 2)
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("exec") {
+        let err = exec::exec(matches.value_of("sh"), &absolute_path(matches.value_of("dir").unwrap()),
+                             &values_option(matches, "command"));
+        fail(&err, 127);
+
+    } else if let Some(matches) = matches.subcommand_matches("export") {
+        let dir = absolute_path(matches.value_of("dir").unwrap());
+        match export::export(matches.value_of("sh"), &dir) {
+            Ok(delta) => {
+                let (output, warnings) = export::format_delta(&delta, matches.value_of("format").unwrap());
+                print!("{}", output);
+                for warning in warnings {
+                    eprintln!("{}", warning);
+                }
+            },
+            Err(err) => fail(&err, 2)
+        }
+
+    } else if matches.is_present("serve") {
+        serve();
 
     } else if matches.is_present("version") {
         println!("{}", VERSION);
     }
 }
+
+// Answer list and compare requests from a bash coprocess until it closes the
+// pipe, see serve.rs. A request that fails gets an empty response, the error
+// message goes to stderr as usual.
+fn serve() {
+    let mut server = serve::Server::default();
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let stdout = io::stdout();
+    let mut writer = stdout.lock();
+
+    while let Some(request) = serve::read_request(&mut reader) {
+        let args = match request {
+            serve::Request::Snapshot(name, text) => {
+                server.put(&name, &text);
+                continue;
+            },
+            serve::Request::Invalid(message) => {
+                if serve::write_response(&mut writer, &serve::error_code(&message)).is_err() {
+                    break;
+                }
+                continue;
+            },
+            serve::Request::Command(args) => args
+        };
+
+        // A panic has already printed its message, there is no code then.
+        let code = panic::catch_unwind(AssertUnwindSafe(|| -> Result<String, String> {
+            let matches = app().get_matches_from_safe(iter::once(String::from("cdenv")).chain(args))
+                .map_err(|error| error.message)?;
+
+            if let Some(matches) = matches.subcommand_matches("list") {
+                Ok(file::list_code(&list_config(matches), matches.value_of("pwd").unwrap(),
+                                   &values_option(matches, "loaded"), log_option(matches).as_deref(),
+                                   matches.value_of("pid").unwrap_or("0"), matches.value_of("cache"),
+                                   &mut server.libraries))
+
+            } else if let Some(matches) = matches.subcommand_matches("compare") {
+                let env_a = server.take(matches.value_of("path").unwrap())?;
+                let env_b = server.take(matches.value_of("input").ok_or("compare needs --input")?)?;
+                environment::compare_snapshots(compare_context(matches), env_a, env_b)
+                    .map_err(|err| err.to_string())
+
+            } else {
+                Err(String::from("only list and compare are available"))
+            }
+        }));
+        let code = match code {
+            Ok(Ok(code)) => code,
+            Ok(Err(message)) => serve::error_code(&message),
+            Err(_) => String::new()
+        };
+
+        if serve::write_response(&mut writer, &code).is_err() {
+            break;
+        }
+    }
+}
+
+// Run one of the store subcommands for a cdenv file.
+fn store_command(dir: &str, command: &str, file: &str) -> io::Result<()> {
+    let store = store::Store::new(dir);
+
+    match command {
+        "path" => {
+            println!("{}", store.path(file)?.display());
+        },
+        "write" => {
+            let mut restore_file = store.open(file)?;
+            io::copy(&mut io::stdin(), &mut restore_file)?;
+        },
+        "read" => {
+            match store.find(file) {
+                Some(path) => {
+                    let mut restore_file = File::open(path)?;
+                    io::copy(&mut restore_file, &mut io::stdout())?;
+                },
+                None => process::exit(1)
+            }
+        },
+        "remove" => {
+            store.remove(file)?;
+        },
+        _ => unreachable!()
+    }
+    Ok(())
+}
+
+// Read the snapshot of the current environment that the shell pipes into
+// blame, why, baseline and drift.
+fn read_snapshot() -> environment::Snapshot {
+    match environment::Snapshot::read(None) {
+        Ok(snapshot) => snapshot,
+        Err(err) => fail(&format!("unable to read the snapshot: {}", err), 2)
+    }
+}
+
+// Print an error message and exit with the status.
+fn fail(message: &str, status: i32) -> ! {
+    eprintln!("{}", message);
+    process::exit(status);
+}
+
+// The options of compare.
+fn compare_context(matches: &ArgMatches) -> CompareContext {
+    let shadow_str = matches.value_of("shadow").unwrap_or("1");
+    CompareContext {
+        store: store::Store::new(matches.value_of("store").unwrap()),
+        file: matches.value_of("file").unwrap().to_string(),
+        redact: match matches.value_of("redact") {
+            Some("") | None => None,
+            Some(redact) => Some(redact.to_string())
+        },
+        log: log_option(matches),
+        pid: matches.value_of("pid").unwrap_or("0").to_string(),
+        loaded: values_option(matches, "loaded"),
+        // Checked by number_value().
+        shadow: shadow_str.parse().unwrap(),
+    }
+}
+
+// The options of list and plan.
+fn list_config(matches: &ArgMatches) -> DiscoveryConfig {
+    DiscoveryConfig {
+        global: match matches.value_of("global").unwrap() {
+            "0" => false,
+            "1" => true,
+            _ => false // simply default to false
+        },
+        reload: matches.is_present("reload"),
+        autoreload: matches.is_present("autoreload"),
+        // Checked by number_value().
+        tag: matches.value_of("tag").unwrap().parse().unwrap(),
+        file: matches.value_of("file").unwrap().to_string(),
+        path: matches.value_of("path").unwrap().to_string(),
+        home: env::var("HOME").unwrap_or_else(|_| String::from("/")),
+        shell: match matches.value_of("shell") {
+            Some("fish") => Shell::Fish,
+            Some("zsh") => Shell::Zsh,
+            Some("sh") => Shell::Sh,
+            _ => Shell::Bash
+        },
+    }
+}
+
+fn values_option(matches: &ArgMatches, name: &str) -> Vec<String> {
+    if matches.is_present(name) {
+        matches.values_of(name).unwrap().map(|x| x.to_string()).collect()
+    } else {
+        vec![]
+    }
+}
+
+// An empty --log option disables the audit log.
+fn log_option(matches: &ArgMatches) -> Option<String> {
+    match matches.value_of("log") {
+        Some("") | None => None,
+        Some(log) => Some(log.to_string())
+    }
+}
+
+// Make a path absolute and remove . and .. the way cd does, without resolving
+// symlinks, so that it can be compared with the paths from CDENV_STACK.
+fn absolute_path(path: &str) -> String {
+    let path = env::current_dir().unwrap().join(path);
+    let mut result = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::ParentDir => { result.pop(); },
+            Component::Normal(name) => result.push(name),
+            _ => {}
+        }
+    }
+    result.display().to_string()
+}
+
+// An invalid time is a usage error like the ones clap reports.
+fn time_option(matches: &ArgMatches, name: &str) -> Option<u64> {
+    matches.value_of(name).map(|value| match audit::parse_time(value) {
+        Some(time) => time,
+        None => {
+            eprintln!("error: Invalid value for '--{} <{}>': invalid time {:?}", name, name, value);
+            process::exit(2);
+        }
+    })
+}
+
+// Validate an option that takes a number. An invalid value is a usage error,
+// also in cdenv serve, which must not exit.
+fn number_value(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("invalid number {:?}", value))
+    }
+}
@@ -0,0 +1,287 @@
+// cdenv - manifest.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use audit::{escape, unescape};
+use store::fnv1a;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Variable,
+    Function,
+    Alias,
+    Option
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Add,
+    Modify,
+    Remove
+}
+
+pub const KINDS: &[Kind] = &[Kind::Variable, Kind::Function, Kind::Alias, Kind::Option];
+pub const ACTIONS: &[Action] = &[Action::Add, Action::Modify, Action::Remove];
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Variable => "variable",
+            Kind::Function => "function",
+            Kind::Alias => "alias",
+            Kind::Option => "option"
+        }
+    }
+
+    pub fn plural(&self) -> &'static str {
+        match self {
+            Kind::Variable => "variables",
+            Kind::Function => "functions",
+            Kind::Alias => "aliases",
+            Kind::Option => "options"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Kind> {
+        KINDS.iter().find(|kind| kind.as_str() == value).cloned()
+    }
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Add => "add",
+            Action::Modify => "modify",
+            Action::Remove => "remove"
+        }
+    }
+
+    fn parse(value: &str) -> Option<Action> {
+        ACTIONS.iter().find(|action| action.as_str() == value).cloned()
+    }
+}
+
+// The value of a name before or after a change. Values that must not be
+// written to disk are only kept as a hash, which is still good enough to tell
+// whether a value has changed.
+#[derive(Clone, PartialEq)]
+pub enum Value {
+    Unset,
+    Plain(String),
+    Hidden(u64)
+}
+
+impl Value {
+    pub fn new(value: Option<&String>, hidden: bool) -> Value {
+        match value {
+            None => Value::Unset,
+            Some(value) if hidden => Value::Hidden(fnv1a(value.as_bytes())),
+            Some(value) => Value::Plain(value.to_string())
+        }
+    }
+
+    pub fn fingerprint(&self) -> Option<u64> {
+        match self {
+            Value::Unset => None,
+            Value::Plain(value) => Some(fnv1a(value.as_bytes())),
+            Value::Hidden(hash) => Some(*hash)
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Value::Unset => String::from("(unset)"),
+            Value::Plain(value) => value.trim_end().to_string(),
+            Value::Hidden(_) => String::from("(redacted)")
+        }
+    }
+
+    fn format(&self) -> String {
+        match self {
+            Value::Unset => String::from("-"),
+            Value::Plain(value) => format!("={}", escape(value)),
+            Value::Hidden(hash) => format!("#{:016x}", hash)
+        }
+    }
+
+    fn parse(value: &str) -> Value {
+        if let Some(value) = value.strip_prefix('=') {
+            Value::Plain(unescape(value))
+        } else if let Some(hash) = value.strip_prefix('#') {
+            u64::from_str_radix(hash, 16).map(Value::Hidden).unwrap_or(Value::Unset)
+        } else {
+            Value::Unset
+        }
+    }
+}
+
+// A single change to the environment made by a cdenv file.
+pub struct Change {
+    pub action: Action,
+    pub kind: Kind,
+    pub name: String,
+    pub old: Value,
+    pub new: Value
+}
+
+impl Change {
+    pub fn new(action: Action, kind: Kind, name: &str, old: Value, new: Value) -> Change {
+        Change {
+            action,
+            kind,
+            name: name.to_string(),
+            old,
+            new
+        }
+    }
+
+    // The event name used in the audit log.
+    pub fn event(&self) -> &'static str {
+        match (self.kind, self.action) {
+            (Kind::Option, Action::Add) => "set-on",
+            (Kind::Option, _) => "set-off",
+            (_, action) => action.as_str()
+        }
+    }
+
+    // The name with the same suffix as used in the debug messages.
+    pub fn display_name(&self) -> String {
+        match self.kind {
+            Kind::Function => format!("{}()", self.name),
+            Kind::Alias => format!("{}*", self.name),
+            _ => self.name.clone()
+        }
+    }
+}
+
+// The place where a name was defined.
+pub struct Location {
+    pub kind: Kind,
+    pub name: String,
+    pub file: String,
+    pub line: usize
+}
+
+// The manifest is stored next to the restore file of a cdenv file and records
+// when the file was loaded and what it changed. It consists of tab-separated
+// lines:
+//
+//   file    <path>
+//   loaded  <timestamp>
+//   mtime   <timestamp>
+//   change  <action> <kind> <name> <old> <new>
+//   location <kind> <name> <line> <file>
+//   leak    <message>
+//
+// The old and new values are either "-" if the name is unset, "=" followed by
+// the escaped value, or "#" followed by the hash of a redacted value.
+pub struct Manifest {
+    pub file: String,
+    pub loaded: u64,
+    pub mtime: u64,
+    pub changes: Vec<Change>,
+    pub locations: Vec<Location>,
+    pub leaks: Vec<String>
+}
+
+impl Manifest {
+    pub fn new(file: &str, changes: Vec<Change>) -> Manifest {
+        Manifest {
+            file: file.to_string(),
+            loaded: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            mtime: mtime(file).unwrap_or(0),
+            changes,
+            locations: vec![],
+            leaks: vec![]
+        }
+    }
+
+    pub fn parse(text: &str) -> Manifest {
+        let mut manifest = Manifest {
+            file: String::new(),
+            loaded: 0,
+            mtime: 0,
+            changes: vec![],
+            locations: vec![],
+            leaks: vec![]
+        };
+
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields[..] {
+                ["file", file] => manifest.file = unescape(file),
+                ["loaded", loaded] => manifest.loaded = loaded.parse().unwrap_or(0),
+                ["mtime", mtime] => manifest.mtime = mtime.parse().unwrap_or(0),
+                ["change", action, kind, name, old, new] => {
+                    if let (Some(action), Some(kind)) = (Action::parse(action), Kind::parse(kind)) {
+                        manifest.changes.push(Change::new(action, kind, name, Value::parse(old),
+                                                          Value::parse(new)));
+                    }
+                },
+                ["location", kind, name, line, file] => {
+                    if let Some(kind) = Kind::parse(kind) {
+                        manifest.locations.push(Location {
+                            kind,
+                            name: name.to_string(),
+                            file: unescape(file),
+                            line: line.parse().unwrap_or(0)
+                        });
+                    }
+                },
+                ["leak", message] => manifest.leaks.push(unescape(message)),
+                _ => {}
+            }
+        }
+
+        manifest
+    }
+
+    pub fn format(&self) -> String {
+        let mut text = format!("file\t{}\nloaded\t{}\nmtime\t{}\n", escape(&self.file),
+                               self.loaded, self.mtime);
+        for change in &self.changes {
+            text.push_str(&format!("change\t{}\t{}\t{}\t{}\t{}\n", change.action.as_str(),
+                                   change.kind.as_str(), change.name, change.old.format(),
+                                   change.new.format()));
+        }
+        for location in &self.locations {
+            text.push_str(&format!("location\t{}\t{}\t{}\t{}\n", location.kind.as_str(),
+                                   location.name, location.line, escape(&location.file)));
+        }
+        for leak in &self.leaks {
+            text.push_str(&format!("leak\t{}\n", escape(leak)));
+        }
+        text
+    }
+
+    // Return true if the cdenv file was modified or removed after it was
+    // loaded.
+    pub fn is_outdated(&self) -> bool {
+        mtime(&self.file) != Some(self.mtime)
+    }
+
+    pub fn count(&self, kind: Kind, action: Action) -> usize {
+        self.changes.iter().filter(|c| c.kind == kind && c.action == action).count()
+    }
+}
+
+pub fn mtime(path: &str) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok()
+}
@@ -0,0 +1,141 @@
+// cdenv - quoting.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// The quoting rules that bash and zsh have in common.
+
+// Follows the quoting of shell code line by line, so that we know whether a
+// statement continues on the next line. A quote is either ', " or $ for $'...'.
+// Here-documents only appear in function definitions.
+#[derive(Default)]
+pub struct Quoting {
+    quote: Option<char>,
+    escape: bool,
+    depth: i32,
+    pending: Vec<(String, bool)>,
+    heredocs: Vec<(String, bool)>
+}
+
+impl Quoting {
+    pub fn feed(&mut self, line: &str) {
+        if let Some((delimiter, strip)) = self.heredocs.first() {
+            let text = if *strip { line.trim_start_matches('\t') } else { line };
+            if text == delimiter {
+                self.heredocs.remove(0);
+            }
+            return;
+        }
+
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            i += 1;
+            if self.escape {
+                self.escape = false;
+                continue;
+            }
+            match (self.quote, c) {
+                (Some('\''), '\'') => self.quote = None,
+                (Some('\''), _) => {},
+                (_, '\\') => self.escape = true,
+                (Some('"'), '"') | (Some('$'), '\'') => self.quote = None,
+                (Some(_), _) => {},
+                (None, '\'') | (None, '"') => self.quote = Some(c),
+                (None, '$') if chars.get(i) == Some(&'\'') => {
+                    self.quote = Some('$');
+                    i += 1;
+                },
+                (None, '(') => self.depth += 1,
+                (None, ')') => self.depth -= 1,
+                (None, '<') if chars.get(i) == Some(&'<') => {
+                    if chars.get(i + 1) == Some(&'<') {
+                        // A here-string.
+                        i += 2;
+                    } else {
+                        i = self.here_document(&chars, i + 1);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        // A backslash at the end of a line only continues it.
+        self.escape = false;
+        self.heredocs.append(&mut self.pending);
+    }
+
+    // Read the delimiter of a here-document that starts after the next
+    // newline and return the index after it.
+    fn here_document(&mut self, chars: &[char], mut i: usize) -> usize {
+        let strip = chars.get(i) == Some(&'-');
+        if strip {
+            i += 1;
+        }
+        while chars.get(i).map(|c| c.is_whitespace()).unwrap_or(false) {
+            i += 1;
+        }
+        let mut delimiter = String::new();
+        while let Some(&c) = chars.get(i) {
+            if c.is_whitespace() || ";&|<>()".contains(c) {
+                break;
+            }
+            if !"'\"\\".contains(c) {
+                delimiter.push(c);
+            }
+            i += 1;
+        }
+        self.pending.push((delimiter, strip));
+        i
+    }
+
+    // Return true if the end of the last line was inside a quoted string or a
+    // here-document.
+    pub fn is_quoted(&self) -> bool {
+        self.quote.is_some() || !self.heredocs.is_empty()
+    }
+
+    // Return true if a variable or alias definition continues on the next
+    // line.
+    pub fn is_open(&self) -> bool {
+        self.is_quoted() || self.depth > 0
+    }
+}
+
+// Remove the quotes from a word that consists of strings in single quotes and
+// escaped characters, as in 'it'\''s'. Return None for any other word.
+pub fn unquote(word: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => loop {
+                match chars.next()? {
+                    '\'' => break,
+                    c => result.push(c)
+                }
+            },
+            '\\' => result.push(chars.next()?),
+            _ => return None
+        }
+    }
+    Some(result)
+}
+
+// Quote a string using single quotes.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
@@ -0,0 +1,153 @@
+// cdenv - serve.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// The line protocol of cdenv serve, which runs as a coprocess of bash, see
+// c:request in cdenv.sh. A request is the number of arguments on a line of its
+// own followed by one line for each argument, the same arguments as on the
+// command line. Backslashes and newlines in arguments are sent as \\ and \n:
+//
+//   3
+//   list
+//   --global=1
+//   ...
+//
+// The response is the number of lines of shell code followed by these lines.
+//
+// The snapshots for compare cannot be passed as files, so they are sent ahead
+// as snapshot requests, which have no response. The snapshot follows the
+// request up to a line that consists of its name only. compare then takes the
+// names instead of filenames, the second one as --input:
+//
+//   2
+//   snapshot
+//   1234
+//   # cdenv bash 5.2
+//   ...
+//   1234
+//
+// A request that cannot be answered, e.g. a line that is not a number where a
+// request should start, gets shell code that prints an error as its response.
+// The server keeps running.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use environment::Snapshot;
+use file::LibraryCache;
+use quoting::quote;
+
+pub enum Request {
+    Snapshot(String, String),
+    Command(Vec<String>),
+    Invalid(String)
+}
+
+// What cdenv serve keeps between two requests.
+#[derive(Default)]
+pub struct Server {
+    pub libraries: LibraryCache,
+    named: HashMap<String, Snapshot>
+}
+
+impl Server {
+    // Parse a snapshot and keep it under its name until it is taken.
+    pub fn put(&mut self, name: &str, text: &str) {
+        self.named.insert(name.to_string(), Snapshot::parse(text));
+    }
+
+    // Return a snapshot that was sent before and forget its name.
+    pub fn take(&mut self, name: &str) -> Result<Snapshot, String> {
+        self.named.remove(name).ok_or_else(|| format!("unknown snapshot {:?}", name))
+    }
+}
+
+// Read the next request, None means that the shell has gone away.
+pub fn read_request(reader: &mut dyn BufRead) -> Option<Request> {
+    let count = read_line(reader)?;
+    let count: usize = match count.parse() {
+        Ok(number) => number,
+        Err(_) => return Some(Request::Invalid(format!("invalid request {:?}", count)))
+    };
+
+    let mut args = vec![];
+    for _ in 0..count {
+        args.push(decode(&read_line(reader)?));
+    }
+
+    if args.len() == 2 && args[0] == "snapshot" {
+        let mut text = String::new();
+        loop {
+            let line = read_line(reader)?;
+            if line == args[1] {
+                break;
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+        Some(Request::Snapshot(args.pop().unwrap(), text))
+    } else {
+        Some(Request::Command(args))
+    }
+}
+
+// Send the shell code for a request.
+pub fn write_response(writer: &mut dyn Write, code: &str) -> io::Result<()> {
+    // Again, lines() would drop carriage returns.
+    let lines: Vec<&str> = match code.strip_suffix('\n').unwrap_or(code) {
+        "" => vec![],
+        code => code.split('\n').collect()
+    };
+    writeln!(writer, "{}", lines.len())?;
+    for line in lines {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()
+}
+
+// The response to a request that cannot be answered.
+pub fn error_code(message: &str) -> String {
+    format!("c.err {}\n", quote(&format!("cdenv serve: {}", message)))
+}
+
+// Undo the escaping of an argument, see c:request.
+fn decode(line: &str) -> String {
+    let mut arg = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => { arg.push('\n'); chars.next(); },
+            ('\\', Some('\\')) => { arg.push('\\'); chars.next(); },
+            (c, _) => arg.push(c)
+        }
+    }
+    arg
+}
+
+// Values may contain carriage returns, so we cannot use lines(). A read error
+// is the same as the end of the input.
+fn read_line(reader: &mut dyn BufRead) -> Option<String> {
+    let mut line = vec![];
+    match reader.read_until(b'\n', &mut line).unwrap_or(0) {
+        0 => None,
+        _ => {
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            Some(String::from_utf8_lossy(&line).to_string())
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// cdenv - sh.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Snapshots from a POSIX shell like dash or busybox sh and the restore
+// statements for it, see cdenv_snapshot in cdenv.posix.sh. A POSIX shell has
+// neither arrays nor a way to list functions and aliases that can be read
+// back, so only variables are tracked. A snapshot consists of the output of
+// export -p and set:
+//
+//   # cdenv sh
+//   # cdenv exports
+//   export NAME='value'
+//   # cdenv variables
+//   NAME='value'
+
+use std::collections::HashSet;
+use std::io::BufRead;
+
+use regex::Regex;
+
+use environment::{parse_state, Dialect, Snapshot};
+use quoting::{quote, Quoting};
+use store::fnv1a;
+
+enum Section {
+    Default,
+    Exports,
+    Variables,
+    State
+}
+
+pub fn parse_environment(reader: &mut dyn BufRead, snapshot: &mut Snapshot) {
+    let re_export = Regex::new("^export ([a-zA-Z_][a-zA-Z0-9_]*)(=(.*))?$").unwrap();
+    let re_variable = Regex::new("^([a-zA-Z_][a-zA-Z0-9_]*)=(.*)$").unwrap();
+
+    let mut section = Section::Default;
+    let mut quoting = Quoting::default();
+    let mut exported = HashSet::new();
+    let mut name = String::new();
+    let mut body = String::new();
+
+    // Values may contain carriage returns, so we cannot use lines().
+    for line in reader.split(b'\n') {
+        let line = String::from_utf8_lossy(&line.unwrap()).to_string();
+
+        if quoting.is_open() {
+            // Collect the lines of a multiline value.
+            quoting.feed(&line);
+            body.push_str(&line);
+            body.push('\n');
+            if !quoting.is_open() && !body.starts_with("export ") {
+                snapshot.vars.insert(name.clone(), body.clone());
+            }
+            continue;
+        }
+
+        if let Some(dialect) = Dialect::parse(&line) {
+            snapshot.dialect = dialect;
+            continue;
+        }
+        match line.as_str() {
+            "# cdenv exports" => section = Section::Exports,
+            "# cdenv variables" => section = Section::Variables,
+            "# cdenv state" => section = Section::State,
+            _ => {
+                quoting = Quoting::default();
+                match (&section, re_export.captures(&line), re_variable.captures(&line)) {
+                    // The values of exported variables are listed by set as
+                    // well, only the names are needed here.
+                    (Section::Exports, Some(groups), _) => {
+                        name = groups.get(1).unwrap().as_str().to_string();
+                        if let Some(value) = groups.get(3) {
+                            quoting.feed(value.as_str());
+                        }
+                        body = format!("{}\n", line);
+                        exported.insert(name.clone());
+                    },
+                    (Section::Variables, _, Some(groups)) => {
+                        name = groups.get(1).unwrap().as_str().to_string();
+                        quoting.feed(groups.get(2).unwrap().as_str());
+                        body = format!("{}\n", line);
+                        if !quoting.is_open() {
+                            snapshot.vars.insert(name.clone(), body.clone());
+                        }
+                    },
+                    (Section::State, _, _) => parse_state(&line, snapshot),
+                    _ => snapshot.unparsed.push(line)
+                }
+            }
+        }
+    }
+
+    // Exported variables without a value are not listed by set.
+    for name in exported {
+        let value = snapshot.vars.entry(name.clone()).or_default();
+        value.push_str(&format!("export {}\n", name));
+    }
+}
+
+// Return the name of one of the helper functions of cdenv, POSIX only allows
+// letters, digits and underscores, e.g. c_debug instead of c.debug.
+pub fn helper(name: &str) -> String {
+    name.replace(['.', ':'], "_")
+}
+
+// Keep a restore statement in a variable instead of the restore file, see
+// restore_statement() in environment.rs. There is one variable for each
+// statement, named after a hash of index.
+pub fn redact_statement(index: &str, statement: &str, output: &mut String) -> String {
+    let name = format!("__cdenv_redacted_{:016x}", fnv1a(index.as_bytes()));
+    output.push_str(&format!("{}={}\n", name, quote(statement)));
+    format!("eval \"${}\"\nunset {}\n", name, name)
+}
@@ -0,0 +1,136 @@
+// cdenv - status.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt::Write;
+
+use audit::format_time;
+use json;
+use manifest::{Action, Manifest, ACTIONS, KINDS};
+use store::Store;
+
+// Describe the loaded cdenv files in the order in which they were loaded
+// together with a summary of the changes each of them made.
+pub fn status(store: &Store, stack: &[String], verbose: bool, as_json: bool) -> String {
+    let layers: Vec<(&String, Option<Manifest>)> = stack.iter()
+        .map(|file| (file, store.read_manifest(file)))
+        .collect();
+
+    if as_json {
+        let layers: Vec<String> = layers.iter()
+            .map(|(file, manifest)| layer_json(file, manifest, verbose))
+            .collect();
+        return format!("{}\n", json::array(&layers));
+    }
+
+    let mut text = String::new();
+    for (file, manifest) in &layers {
+        writeln!(text, "{}", file).unwrap();
+
+        let manifest = match manifest {
+            Some(manifest) => manifest,
+            None => {
+                writeln!(text, "    not loaded").unwrap();
+                continue;
+            }
+        };
+
+        writeln!(text, "    loaded {}{}", format_time(manifest.loaded),
+                       if manifest.is_outdated() { ", changed on disk since" } else { "" }).unwrap();
+
+        if manifest.changes.is_empty() {
+            writeln!(text, "    no changes").unwrap();
+        }
+
+        // Changes that unloading the file will not undo.
+        for leak in &manifest.leaks {
+            writeln!(text, "    leaked     {}", leak).unwrap();
+        }
+
+        for kind in KINDS {
+            if !manifest.changes.iter().any(|c| c.kind == *kind) {
+                continue;
+            }
+
+            writeln!(text, "    {:<10} {} added, {} modified, {} removed", kind.plural(),
+                           manifest.count(*kind, Action::Add),
+                           manifest.count(*kind, Action::Modify),
+                           manifest.count(*kind, Action::Remove)).unwrap();
+
+            if verbose {
+                for change in manifest.changes.iter().filter(|c| c.kind == *kind) {
+                    let sign = match change.action {
+                        Action::Add => '+',
+                        Action::Modify => '~',
+                        Action::Remove => '-'
+                    };
+                    writeln!(text, "        {} {}", sign, change.name).unwrap();
+                }
+            }
+        }
+    }
+    text
+}
+
+fn layer_json(file: &str, manifest: &Option<Manifest>, verbose: bool) -> String {
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => return json::object(&[("file", json::string(file)),
+                                      ("loaded", String::from("null"))])
+    };
+
+    let mut changes = vec![];
+    for kind in KINDS {
+        let mut fields = vec![];
+        for action in ACTIONS {
+            fields.push((action_key(*action), manifest.count(*kind, *action).to_string()));
+        }
+        if verbose {
+            for action in ACTIONS {
+                let names: Vec<String> = manifest.changes.iter()
+                    .filter(|c| c.kind == *kind && c.action == *action)
+                    .map(|c| json::string(&c.name))
+                    .collect();
+                fields.push((names_key(*action), json::array(&names)));
+            }
+        }
+        changes.push((kind.plural(), json::object(&fields)));
+    }
+
+    json::object(&[
+        ("file", json::string(file)),
+        ("loaded", manifest.loaded.to_string()),
+        ("changed", manifest.is_outdated().to_string()),
+        ("changes", json::object(&changes)),
+        ("leaks", json::array(&manifest.leaks.iter().map(|x| json::string(x)).collect::<Vec<_>>()))
+    ])
+}
+
+fn action_key(action: Action) -> &'static str {
+    match action {
+        Action::Add => "added",
+        Action::Modify => "modified",
+        Action::Remove => "removed"
+    }
+}
+
+fn names_key(action: Action) -> &'static str {
+    match action {
+        Action::Add => "added_names",
+        Action::Modify => "modified_names",
+        Action::Remove => "removed_names"
+    }
+}
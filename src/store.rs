@@ -0,0 +1,274 @@
+// cdenv - store.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader};
+use std::io::prelude::*;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use audit::{escape, unescape};
+use manifest::Manifest;
+
+// The restore store is a directory with one restore file for each cdenv file
+// that is currently loaded. The restore files are named after a hash of the
+// path of the cdenv file, so that the names have a fixed length. An index file
+// maps the names back to the paths, and resolves the rare hash collisions by
+// appending a counter to the name.
+pub struct Store {
+    dir: PathBuf
+}
+
+impl Store {
+    pub fn new(dir: &str) -> Store {
+        Store {
+            dir: PathBuf::from(dir)
+        }
+    }
+
+    // Return the path of the restore file for a cdenv file and register it in
+    // the index if necessary.
+    pub fn path(&self, file: &str) -> io::Result<PathBuf> {
+        let mut index = self.read_index();
+
+        let name = match index.iter().find(|(_, path)| path == file) {
+            Some((name, _)) => name.clone(),
+            None => {
+                let hash = format!("{:016x}", fnv1a(file.as_bytes()));
+                let mut name = hash.clone();
+                let mut counter = 0;
+                while index.iter().any(|(n, _)| *n == name) {
+                    counter += 1;
+                    name = format!("{}-{}", hash, counter);
+                }
+                index.push((name.clone(), file.to_string()));
+                self.write_index(&index)?;
+                name
+            }
+        };
+
+        Ok(self.dir.join(name))
+    }
+
+    // Return the path of the restore file for a cdenv file if it exists.
+    pub fn find(&self, file: &str) -> Option<PathBuf> {
+        self.read_index().into_iter()
+            .find(|(_, path)| path == file)
+            .map(|(name, _)| self.dir.join(name))
+            .filter(|path| path.exists())
+    }
+
+    // Open the restore file for a cdenv file for appending.
+    pub fn open(&self, file: &str) -> io::Result<File> {
+        let path = self.path(file)?;
+        OpenOptions::new().append(true).create(true).mode(0o600).open(&path)
+    }
+
+    // Store the manifest for a cdenv file next to its restore file.
+    pub fn write_manifest(&self, manifest: &Manifest) -> io::Result<()> {
+        let path = manifest_path(&self.path(&manifest.file)?);
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+            .open(path)?;
+        file.write_all(manifest.format().as_bytes())
+    }
+
+    // Return the manifest for a cdenv file if it is loaded.
+    pub fn read_manifest(&self, file: &str) -> Option<Manifest> {
+        let path = self.find(file)?;
+        let text = fs::read_to_string(manifest_path(&path)).ok()?;
+        Some(Manifest::parse(&text))
+    }
+
+    // Remove the restore file for a cdenv file and its entry from the index.
+    pub fn remove(&self, file: &str) -> io::Result<()> {
+        let mut index = self.read_index();
+        if let Some(i) = index.iter().position(|(_, path)| path == file) {
+            let (name, _) = index.remove(i);
+            let path = self.dir.join(name);
+            let _ = fs::remove_file(manifest_path(&path));
+            let _ = fs::remove_file(path);
+            self.write_index(&index)?;
+        }
+        Ok(())
+    }
+
+    // The path of the file with the fingerprints of the environment at the
+    // time cdenv was initialized.
+    pub fn baseline_path(&self) -> PathBuf {
+        self.dir.join("baseline")
+    }
+
+    fn read_index(&self) -> Vec<(String, String)> {
+        let file = match File::open(self.dir.join("index")) {
+            Ok(file) => file,
+            Err(_) => return vec![]
+        };
+
+        let mut index = vec![];
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            if let Some((name, path)) = line.split_once('\t') {
+                index.push((name.to_string(), unescape(path)));
+            }
+        }
+        index
+    }
+
+    fn write_index(&self, index: &[(String, String)]) -> io::Result<()> {
+        DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+
+        let mut buffer = String::new();
+        for (name, path) in index {
+            buffer.push_str(&format!("{}\t{}\n", name, escape(path)));
+        }
+
+        // Replace the index atomically.
+        let tmp = self.dir.join("index.tmp");
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+            .open(&tmp)?;
+        file.write_all(buffer.as_bytes())?;
+        fs::rename(&tmp, self.dir.join("index"))
+    }
+}
+
+fn manifest_path(path: &Path) -> PathBuf {
+    path.with_extension("manifest")
+}
+
+// Create the restore directory for a shell process in the cache directory and
+// put the start time of the process and the name of the host in it. A
+// leftover directory from a dead process with the same pid is cleared first.
+// The directory of a process on another host that shares the cache directory
+// is never touched, which is an error because the shell cannot use it.
+pub fn claim(cache: &str, pid: &str) -> io::Result<()> {
+    let dir = Path::new(cache).join(pid);
+    let stamp = dir.join("starttime");
+    let host = hostname();
+    let starttime = format!("{}\t{}", process_starttime(pid).unwrap_or_default(), host);
+
+    match fs::read_to_string(&stamp).ok().as_deref().map(parse_stamp) {
+        Some((_, Some(other))) if other != host => {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} belongs to a shell on {}", dir.display(), other)));
+        },
+        Some(_) if fs::read_to_string(&stamp).ok() != Some(starttime.clone()) => {
+            let _ = fs::remove_dir_all(&dir);
+        },
+        None if dir.exists() => {
+            let _ = fs::remove_dir_all(&dir);
+        },
+        _ => ()
+    }
+
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    for path in &[Path::new(cache), &dir] {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    }
+    fs::write(&stamp, starttime)
+}
+
+// Remove the restore directories of all shell processes on this host that no
+// longer exist. A directory whose pid has been reused by another process is
+// detected by comparing the start times. The cache directory may be shared
+// with other hosts, e.g. ~/.cache on NFS, whose processes we cannot see, so
+// their directories are skipped.
+pub fn collect_garbage(cache: &str) {
+    // We need /proc to tell whether a process is still alive.
+    if !Path::new("/proc/self/stat").exists() {
+        return;
+    }
+
+    let entries = match fs::read_dir(cache) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    let host = hostname();
+
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_string_lossy().to_string();
+        if pid.is_empty() || !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+
+        let stamp = fs::read_to_string(entry.path().join("starttime")).ok();
+        let stale = match stamp.as_deref().map(parse_stamp) {
+            Some((_, Some(other))) if other != host => false,
+            Some((stamp, _)) => process_starttime(&pid).map(|starttime| starttime != stamp).unwrap_or(true),
+            // Directories from older versions without a stamp are
+            // only removed once the process is gone.
+            None => process_starttime(&pid).is_none()
+        };
+
+        if stale {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+// Split a stamp into the start time and the host. Stamps from older versions
+// have no host.
+fn parse_stamp(stamp: &str) -> (&str, Option<&str>) {
+    match stamp.split_once('\t') {
+        Some((starttime, host)) => (starttime, Some(host)),
+        None => (stamp, None)
+    }
+}
+
+// The name of this host, empty if it cannot be determined.
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim_end().to_string())
+        .unwrap_or_default()
+}
+
+// Collect garbage at most once an hour, which is cheap enough to be done on
+// every prompt.
+pub fn collect_garbage_if_due(cache: &str) {
+    let stamp = Path::new(cache).join("gc");
+    let due = match fs::metadata(&stamp).and_then(|m| m.modified()) {
+        Ok(mtime) => match SystemTime::now().duration_since(mtime) {
+            Ok(age) => age > Duration::from_secs(3600),
+            Err(_) => false
+        },
+        Err(_) => true
+    };
+
+    if due && fs::write(&stamp, "").is_ok() {
+        collect_garbage(cache);
+    }
+}
+
+// Return the start time of a process in clock ticks since boot, which is the
+// 22nd field in /proc/<pid>/stat. The second field is the command name in
+// parentheses which may contain spaces, so we start after the closing paren.
+fn process_starttime(pid: &str) -> Option<String> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19).map(|x| x.to_string())
+}
+
+// The 64-bit FNV-1a hash function. Unlike the hashers from the standard
+// library it is guaranteed to produce the same result with every version of
+// cdenv.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
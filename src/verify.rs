@@ -0,0 +1,79 @@
+// cdenv - verify.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::process::Stdio;
+
+use batch;
+use environment::{diff, is_volatile, Snapshot};
+use manifest::{Action, Kind};
+
+// The script loads all cdenv files for a directory the same way an interactive
+// shell does and unloads them again using the restore files. The snapshots
+// before and after go to stdout, separated by a null byte, so that they never
+// touch the disk. The output of the cdenv files is discarded.
+const SCRIPT: &str = r#"
+builtin cd "$1" || exit 2
+exec 3>&1 >/dev/null
+c:snapshot >&3
+printf '\0' >&3
+cdenv update
+for ((__cdenv_i=${#CDENV_STACK[@]}-1; __cdenv_i>=0; __cdenv_i--)); do
+    c:unsource "${CDENV_STACK[__cdenv_i]}"
+done
+unset __cdenv_i
+c:snapshot >&3
+"#;
+
+// Check that loading all cdenv files for a directory and unloading them again
+// leaves the environment as it was. Every difference is returned as a leak.
+pub fn verify(sh: Option<&str>, dir: &str) -> Result<Vec<String>, String> {
+    let tmp = batch::TempDir::new("verify")?;
+
+    // Warnings about leaks on stderr are of interest.
+    let output = batch::command(sh, tmp.path(), SCRIPT)?
+        .arg(dir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| format!("unable to run bash: {}", err))?;
+    drop(tmp);
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    let (mut env_a, mut env_b) = match text.split_once('\0') {
+        Some((before, after)) if output.status.success() => (Snapshot::parse(before), Snapshot::parse(after)),
+        _ => return Err(format!("unable to load and unload {}", dir))
+    };
+
+    for snapshot in [&mut env_a, &mut env_b] {
+        snapshot.vars.retain(|name, _| !is_volatile(name));
+    }
+
+    let leaks = diff(&env_a, &env_b).changes.iter().map(|change| {
+        let what = match change.action {
+            Action::Add => "was left behind",
+            Action::Modify => "was not restored",
+            Action::Remove => "was not restored"
+        };
+        if change.kind == Kind::Variable {
+            format!("{} {} {}: {} -> {}", change.kind.as_str(), change.name, what,
+                    change.old.describe(), change.new.describe())
+        } else {
+            format!("{} {} {}", change.kind.as_str(), change.display_name(), what)
+        }
+    }).collect();
+    Ok(leaks)
+}
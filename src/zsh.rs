@@ -0,0 +1,203 @@
+// cdenv - zsh.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Snapshots from zsh and the restore statements for it, see c:snapshot in
+// cdenv.zsh. A snapshot consists of the output of typeset -p for all
+// parameters that are not special, the output of functions and alias -L, and
+// a list of all options.
+
+use std::io::BufRead;
+
+use regex::{Captures, Regex};
+
+use environment::{parse_state, Dialect, Snapshot};
+use manifest::{Kind, Value};
+use quoting::{quote, Quoting};
+
+enum LineState {
+    Default,
+    InVariableDef,
+    InFunctionDef,
+    InAliasDef,
+    InOptions,
+    InState
+}
+
+pub fn parse_environment(reader: &mut dyn BufRead, snapshot: &mut Snapshot) {
+    let re_typeset = Regex::new("^(typeset|export|integer|float|readonly)((?:\\s+-\\S+)*)\\s+([a-zA-Z_][a-zA-Z0-9_]*)(=(.*))?$").unwrap();
+    let re_array = Regex::new("^([a-zA-Z_][a-zA-Z0-9_]*)=(\\(.*)$").unwrap();
+    let re_function_start = Regex::new("^(\\S+) \\(\\) \\{$").unwrap();
+    let re_alias = Regex::new("^alias\\s+(?:-[gs]\\s+)?(?:--\\s+)?([^=\\s]+)=(.*)$").unwrap();
+
+    let mut line_state = LineState::Default;
+    let mut quoting = Quoting::default();
+    let mut name = String::new();
+    let mut body = String::new();
+    let mut options: Vec<String> = vec![];
+
+    fn get_group(groups: &Captures, group: usize) -> String {
+        groups.get(group).unwrap().as_str().to_string()
+    }
+
+    // Values may contain carriage returns, so we cannot use lines().
+    for line in reader.split(b'\n') {
+        let line = String::from_utf8_lossy(&line.unwrap()).to_string();
+
+        match line_state {
+            LineState::InVariableDef | LineState::InAliasDef | LineState::InFunctionDef => {
+                // Collect the lines of a multiline value or of a function.
+                quoting.feed(&line);
+                body.push_str(&line);
+                body.push('\n');
+                let done = match line_state {
+                    LineState::InFunctionDef => line == "}" && !quoting.is_quoted(),
+                    _ => !quoting.is_open()
+                };
+                if done {
+                    match line_state {
+                        LineState::InVariableDef => snapshot.vars.insert(name.clone(), body.clone()),
+                        LineState::InAliasDef => snapshot.aliases.insert(name.clone(), body.clone()),
+                        _ => snapshot.funcs.insert(name.clone(), body.clone())
+                    };
+                    body.clear();
+                    line_state = LineState::Default;
+                }
+                continue;
+            },
+            _ => {}
+        }
+
+        if let Some(dialect) = Dialect::parse(&line) {
+            snapshot.dialect = dialect;
+            continue;
+        } else if line == "# cdenv options" {
+            line_state = LineState::InOptions;
+            continue;
+        } else if line == "# cdenv state" {
+            line_state = LineState::InState;
+            continue;
+        }
+
+        match line_state {
+            LineState::InOptions => {
+                if let Some((option, "on")) = line.split_once(' ') {
+                    options.push(option.to_string());
+                }
+                continue;
+            },
+            LineState::InState => {
+                parse_state(&line, snapshot);
+                continue;
+            },
+            _ => {}
+        }
+
+        quoting = Quoting::default();
+
+        if let Some(groups) = re_typeset.captures(&line) {
+            name = get_group(&groups, 3);
+            body = format!("typeset {} {}", flags(&get_group(&groups, 1), &get_group(&groups, 2)), name);
+            match groups.get(5) {
+                None => {
+                    // Arrays are declared first and assigned on the next
+                    // line.
+                    body.push('\n');
+                    snapshot.vars.insert(name.clone(), body.clone());
+                    body.clear();
+                },
+                Some(value) => {
+                    quoting.feed(value.as_str());
+                    body.push_str(&format!("={}\n", value.as_str()));
+                    line_state = LineState::InVariableDef;
+                }
+            }
+
+        } else if let Some(groups) = re_array.captures(&line).filter(|g| get_group(g, 1) == name) {
+            // The assignment that follows the declaration of an array.
+            quoting.feed(&get_group(&groups, 2));
+            body = snapshot.vars.remove(&name).unwrap_or_default();
+            body.push_str(&format!("{}\n", line));
+            line_state = LineState::InVariableDef;
+
+        } else if let Some(groups) = re_function_start.captures(&line) {
+            name = get_group(&groups, 1);
+            body = format!("{}\n", line);
+            line_state = LineState::InFunctionDef;
+
+        } else if let Some(groups) = re_alias.captures(&line) {
+            name = get_group(&groups, 1);
+            quoting.feed(&get_group(&groups, 2));
+            body = format!("{}\n", line);
+            line_state = LineState::InAliasDef;
+
+        } else {
+            snapshot.unparsed.push(line);
+            continue;
+        }
+
+        // Single line definitions are complete right away.
+        if let LineState::InVariableDef | LineState::InAliasDef = line_state {
+            if !quoting.is_open() {
+                match line_state {
+                    LineState::InVariableDef => snapshot.vars.insert(name.clone(), body.clone()),
+                    _ => snapshot.aliases.insert(name.clone(), body.clone())
+                };
+                body.clear();
+                line_state = LineState::Default;
+            }
+        }
+    }
+
+    // The options are kept in the same form as SHELLOPTS in bash, so that
+    // they are compared the same way.
+    options.sort();
+    snapshot.vars.insert(String::from("SHELLOPTS"), options.join(":"));
+}
+
+// Turn the command and the flags that typeset -p prints into flags for
+// typeset -g, so that restoring the parameter inside a function does not
+// make it local.
+fn flags(command: &str, flags: &str) -> String {
+    let mut result = vec!["-g"];
+    match command {
+        "export" => result.push("-x"),
+        "integer" => result.push("-i"),
+        "float" => result.push("-F"),
+        "readonly" => result.push("-r"),
+        _ => {}
+    }
+    result.extend(flags.split_whitespace().filter(|flag| *flag != "-g"));
+    result.join(" ")
+}
+
+// Return the statement that removes a name. value is the definition of the
+// name, which tells suffix aliases apart.
+pub fn unset_statement(kind: Kind, key: &str, value: &Value) -> String {
+    match (kind, value) {
+        (Kind::Function, _) => format!("unfunction -- {}\n", quote(key)),
+        (Kind::Alias, Value::Plain(value)) if value.starts_with("alias -s ") => {
+            format!("unalias -s -- {}\n", quote(key))
+        },
+        (Kind::Alias, _) => format!("unalias -- {}\n", quote(key)),
+        _ => format!("unset {}\n", key)
+    }
+}
+
+// Return the statement that switches an option on or off.
+pub fn option_statement(key: &str, on: bool) -> String {
+    format!("{} {}\n", if on { "setopt" } else { "unsetopt" }, key)
+}
@@ -0,0 +1,158 @@
+// cdenv - tests/common/mod.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// A fixture is a temporary directory with a home directory, a directory
+// outside of it and the runtime and state directories for cdenv. Scripts run
+// in a clean bash with cdenv.sh sourced, or in dash, zsh or fish with
+// cdenv.posix.sh, cdenv.zsh or cdenv.fish, and the cdenv executable of this
+// build.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Defined in every script, go changes to a directory relative to the fixture
+// and does what the prompt command of an interactive shell would do.
+const PRELUDE: &str = r#"
+source "$CDENV_TEST_SH" ""
+go() { cd "$CDENV_TEST_ROOT/$1" && cdenv update; }
+stack() { local path; for path in "${CDENV_STACK[@]}"; do echo "${path#$CDENV_TEST_ROOT/}"; done; }
+"#;
+
+// The same for a POSIX shell, where cd does what the prompt command does.
+const POSIX_PRELUDE: &str = r#"
+. "$CDENV_TEST_SH"
+go() { cd "$CDENV_TEST_ROOT/$1"; }
+"#;
+
+// Whether a program is found in PATH, the tests for other shells fail early if
+// theirs is not installed.
+pub fn installed(program: &str) -> bool {
+    std::env::var("PATH").unwrap_or_default().split(':')
+        .any(|dir| !dir.is_empty() && std::path::Path::new(dir).join(program).is_file())
+}
+
+// The same for zsh. cd -q does not run the chpwd hook, which would do the
+// same as cdenv update.
+const ZSH_PRELUDE: &str = r#"
+source "$CDENV_TEST_SH" ""
+go() { builtin cd -q "$CDENV_TEST_ROOT/$1" && cdenv update; }
+stack() { local file; for file in "${CDENV_STACK[@]}"; do print -r -- "${file#$CDENV_TEST_ROOT/}"; done; }
+"#;
+
+// The same for fish. cdenv update does nothing if the handler for PWD has
+// already done it.
+const FISH_PRELUDE: &str = r#"
+source $CDENV_TEST_SH
+function go; cd $CDENV_TEST_ROOT/$argv[1]; and cdenv update; end
+function stack; string replace -- $CDENV_TEST_ROOT/ '' $CDENV_STACK; end
+"#;
+
+pub struct Fixture {
+    pub root: PathBuf
+}
+
+impl Fixture {
+    pub fn new(name: &str) -> Fixture {
+        let root = std::env::temp_dir().join(format!("cdenv-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in &["home", "outside", "libs", "run", "state"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let fixture = Fixture { root };
+        fixture.rc("");
+        fixture
+    }
+
+    // Write a file relative to the fixture root, e.g. "home/proj/.cdenv.sh".
+    pub fn file(&self, path: &str, content: &str) {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    // Write ~/.cdenvrc.sh with additional settings. The settings for the
+    // other shells only get the executable.
+    pub fn rc(&self, settings: &str) {
+        self.file("home/.cdenvrc.sh", &format!("CDENV_EXEC={}\nCDENV_PATH={}\n{}\n",
+                                               env!("CARGO_BIN_EXE_cdenv"),
+                                               self.root.join("libs").display(), settings));
+        self.file("home/.cdenvrc.posix.sh", &format!("CDENV_EXEC={}\n", env!("CARGO_BIN_EXE_cdenv")));
+        self.file("home/.cdenvrc.zsh", &format!("CDENV_EXEC={}\n", env!("CARGO_BIN_EXE_cdenv")));
+        self.file("home/.cdenvrc.fish", &format!("set -g CDENV_EXEC {}\n", env!("CARGO_BIN_EXE_cdenv")));
+    }
+
+    // Run a script and return its output. The script fails if it writes
+    // anything to stderr.
+    pub fn run(&self, script: &str) -> String {
+        self.execute(Command::new("bash").args(["--norc", "--noprofile", "-c", &format!("{}{}", PRELUDE, script)]),
+                     script, "cdenv.sh")
+    }
+
+    // Run a script in dash with cdenv.posix.sh, see run().
+    pub fn run_posix(&self, script: &str) -> String {
+        self.execute(Command::new("dash").args(["-c", &format!("{}{}", POSIX_PRELUDE, script)]),
+                     script, "cdenv.posix.sh")
+    }
+
+    // Run a script in zsh with cdenv.zsh, see run().
+    pub fn run_zsh(&self, script: &str) -> String {
+        self.execute(Command::new("zsh").args(["-f", "-c", &format!("{}{}", ZSH_PRELUDE, script)]),
+                     script, "cdenv.zsh")
+    }
+
+    // Run a script in fish with cdenv.fish, see run().
+    pub fn run_fish(&self, script: &str) -> String {
+        self.execute(Command::new("fish").args(["--no-config", "-c", &format!("{}{}", FISH_PRELUDE, script)]),
+                     script, "cdenv.fish")
+    }
+
+    fn execute(&self, command: &mut Command, script: &str, sh: &str) -> String {
+        let output = command
+            .current_dir(self.root.join("home"))
+            .env_clear()
+            .env("PATH", std::env::var("PATH").unwrap())
+            .env("TERM", "dumb")
+            .env("HOME", self.root.join("home"))
+            .env("XDG_RUNTIME_DIR", self.root.join("run"))
+            .env("XDG_STATE_HOME", self.root.join("state"))
+            .env("CDENV_TEST_SH", format!("{}/{}", env!("CARGO_MANIFEST_DIR"), sh))
+            .env("CDENV_TEST_ROOT", &self.root)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert!(output.status.success() && stderr.is_empty(),
+                "script failed:\n{}\nstdout:\n{}\nstderr:\n{}", script, stdout, stderr);
+        stdout
+    }
+
+    // Return the lines of a file relative to the fixture root.
+    pub fn lines(&self, path: &str) -> Vec<String> {
+        fs::read_to_string(self.root.join(path)).unwrap_or_default()
+            .lines().map(|x| x.to_string()).collect()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
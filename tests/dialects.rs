@@ -0,0 +1,374 @@
+// cdenv - tests/dialects.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Tests for snapshots from different versions of bash, from posix mode, from
+// zsh and from fish, see tests/fixtures.
+
+extern crate cdenv;
+
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use cdenv::environment::{Dialect, Shell};
+use cdenv::{diff, discover, DiscoveryConfig, Snapshot};
+use cdenv::fish::quote as fish_quote;
+use common::Fixture;
+
+const FIXTURES: &[(&str, u32, u32, bool)] = &[
+    ("bash-4.0", 4, 0, false),
+    ("bash-4.3", 4, 3, false),
+    ("bash-4.4", 4, 4, false),
+    ("bash-5.1", 5, 1, false),
+    ("bash-5.2", 5, 2, false),
+    ("bash-5.2-posix", 5, 2, true)
+];
+
+fn fixture(name: &str) -> Snapshot {
+    let path = format!("{}/tests/fixtures/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+    Snapshot::parse(&fs::read_to_string(path).unwrap())
+}
+
+// Evaluate the parsed statements in the bash at hand and print the result.
+fn evaluate(snapshot: &Snapshot) -> String {
+    let mut script = String::new();
+    for map in &[&snapshot.vars, &snapshot.funcs, &snapshot.aliases] {
+        let mut statements: Vec<&String> = map.values().collect();
+        statements.sort();
+        for statement in statements {
+            script.push_str(statement);
+        }
+    }
+    script.push_str("declare -p A C D E I M X\ndeclare -f f\nalias\n");
+
+    let output = Command::new("bash").args(["--norc", "--noprofile", "-c", &script])
+        .env_clear().output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+// Run a definitions file and return the name of the fixture from its header
+// line, e.g. "bash-5.2-posix", together with the output.
+fn capture(command: &[&str]) -> (String, String) {
+    let output = Command::new(command[0]).args(&command[1..])
+        .current_dir(env!("CARGO_MANIFEST_DIR")).env_clear().output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let text = String::from_utf8_lossy(&output.stdout).to_string();
+    // Only major and minor version are part of the name, e.g. "zsh-5.0" for
+    // zsh 5.0.8.
+    let header: Vec<&str> = text.lines().next().unwrap_or_default()
+        .trim_start_matches("# cdenv ").split(' ').collect();
+    let version: Vec<&str> = header.get(1).unwrap_or(&"").splitn(3, '.').take(2).collect();
+    let mut name = format!("{}-{}", header[0], version.join("."));
+    for word in &header[2..] {
+        name = format!("{}-{}", name, word);
+    }
+    (name, text)
+}
+
+// The fixture for the version of a shell that is installed must be the same
+// as a capture, see tests/fixtures/README.md.
+fn assert_captured(command: &[&str]) {
+    let (name, text) = capture(command);
+    let path = format!("{}/tests/fixtures/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+    if let Ok(fixture) = fs::read_to_string(&path) {
+        assert_eq!(text, fixture, "{} differs from a capture with {}", path, command[0]);
+    }
+}
+
+#[test]
+fn fixtures_are_captured_from_bash() {
+    assert_captured(&["bash", "--norc", "--noprofile", "tests/fixtures/definitions.sh"]);
+    assert_captured(&["bash", "--norc", "--noprofile", "tests/fixtures/definitions.sh", "posix"]);
+}
+
+// The tests that need zsh or fish are ignored unless they are asked for, see
+// make test-shells.
+#[test]
+#[ignore = "needs zsh"]
+fn fixtures_are_captured_from_zsh() {
+    assert!(common::installed("zsh"), "zsh is not installed");
+    assert_captured(&["zsh", "-f", "tests/fixtures/definitions.zsh"]);
+}
+
+#[test]
+#[ignore = "needs fish"]
+fn fixtures_are_captured_from_fish() {
+    assert!(common::installed("fish"), "fish is not installed");
+    assert_captured(&["fish", "--no-config", "tests/fixtures/definitions.fish"]);
+}
+
+#[test]
+fn fixtures_are_parsed_completely() {
+    for (name, major, minor, posix) in FIXTURES {
+        let snapshot = fixture(name);
+        let dialect = Dialect { shell: Shell::Bash, major: *major, minor: *minor, posix: *posix };
+        assert_eq!(snapshot.dialect, dialect, "{}", name);
+        assert!(snapshot.unparsed.is_empty(), "{}: {:?}", name, snapshot.unparsed);
+
+        let mut names: Vec<&String> = snapshot.vars.keys().collect();
+        names.sort();
+        assert_eq!(names, ["A", "C", "D", "E", "I", "M", "X"], "{}", name);
+        assert!(snapshot.funcs.contains_key("f"), "{}", name);
+        let mut names: Vec<&String> = snapshot.aliases.keys().collect();
+        names.sort();
+        assert_eq!(names, ["a", "m", "q"], "{}", name);
+    }
+}
+
+#[test]
+fn fixtures_restore_the_same_definitions() {
+    let expected = evaluate(&fixture("bash-5.2"));
+    for (name, _, _, _) in FIXTURES {
+        assert_eq!(evaluate(&fixture(name)), expected, "{}", name);
+    }
+}
+
+#[test]
+fn quoted_arrays_are_unquoted() {
+    let snapshot = fixture("bash-4.3");
+    assert_eq!(snapshot.vars["A"], "declare -ga A=([0]=\"1\" [1]=\"x y\" [2]=\"it's\")\n");
+    assert_eq!(snapshot.vars["M"], "declare -gA M=([k]=\"v\" [\"a b\"]=\"c\" )\n");
+}
+
+#[test]
+fn snapshots_without_header_are_current() {
+    let snapshot = Snapshot::parse("declare -- X=\"y\"\nx='y'\n");
+    assert_eq!(snapshot.dialect, Dialect::CURRENT);
+    assert_eq!(snapshot.unparsed, ["x='y'"]);
+}
+
+#[test]
+fn posix_mode_is_undone() {
+    let fixture = Fixture::new("posix");
+    fixture.file("home/proj/.cdenv.sh", "set -o posix\nalias ll='ls -l'\nFOO=bar\n");
+
+    let output = fixture.run(r#"
+alias la='ls -a'
+go home/proj
+alias
+go home
+[[ -o posix ]] || echo noposix
+alias
+echo "${FOO-unset}"
+"#);
+
+    assert_eq!(output, "la='ls -a'\nll='ls -l'\nnoposix\nalias la='ls -a'\nunset\n");
+}
+
+#[test]
+fn zsh_fixtures_are_parsed_completely() {
+    for (name, major, minor) in &[("zsh-5.0", 5, 0), ("zsh-5.9", 5, 9)] {
+        let snapshot = fixture(name);
+        let dialect = Dialect { shell: Shell::Zsh, major: *major, minor: *minor, posix: false };
+        assert_eq!(snapshot.dialect, dialect, "{}", name);
+        assert!(snapshot.unparsed.is_empty(), "{}: {:?}", name, snapshot.unparsed);
+
+        let mut names: Vec<&String> = snapshot.vars.keys().collect();
+        names.sort();
+        assert_eq!(names, ["A", "C", "D", "E", "I", "M", "SHELLOPTS", "X"], "{}", name);
+        assert_eq!(snapshot.vars["SHELLOPTS"], "aliases:nomatch", "{}", name);
+        assert_eq!(snapshot.vars["E"], "typeset -g -x E='say \"hi\" $HOME'\n", "{}", name);
+        assert_eq!(snapshot.funcs["f"], "f () {\n\tcat <<'END'\n}\nEND\n}\n", "{}", name);
+        let mut names: Vec<&String> = snapshot.aliases.keys().collect();
+        names.sort();
+        assert_eq!(names, ["G", "a", "m", "q", "txt"], "{}", name);
+    }
+
+    let snapshot = fixture("zsh-5.0");
+    assert_eq!(snapshot.vars["A"], "typeset -g -a A\nA=(1 'x y' 'it'\\''s')\n");
+    assert_eq!(snapshot.vars["I"], "typeset -g -i I=3\n");
+}
+
+#[test]
+fn zsh_changes_are_undone_with_zsh_statements() {
+    let before = fixture("zsh-5.9");
+    let text = fs::read_to_string(format!("{}/tests/fixtures/zsh-5.9.txt", env!("CARGO_MANIFEST_DIR")))
+        .unwrap()
+        .replace("export E='say \"hi\" $HOME'\n", "")
+        .replace("typeset -g -i I=3", "typeset -g -i I=4")
+        .replace("alias -s txt=less\n", "alias -s log=less\n")
+        .replace("autocd off", "autocd on")
+        .replace("aliases on", "aliases off")
+        .replace("f () {", "g () {\n\ttrue\n}\nf () {");
+    let after = Snapshot::parse(&text);
+    assert!(after.unparsed.is_empty(), "{:?}", after.unparsed);
+
+    let restore = diff(&before, &after).render(&before).restore;
+    for statement in &["typeset -g -x E='say \"hi\" $HOME'\n", "unset I\ntypeset -g -i I=3\n",
+                       "unfunction -- 'g'\n", "unalias -s -- 'log'\n", "alias -s txt=less\n",
+                       "unsetopt autocd\n", "setopt aliases\n"] {
+        assert!(restore.contains(statement), "{:?} not in:\n{}", statement, restore);
+    }
+    assert!(!restore.contains("shopt"), "{}", restore);
+}
+
+// A session in a real zsh.
+#[test]
+#[ignore = "needs zsh"]
+fn zsh_session_loads_and_undoes_changes() {
+    assert!(common::installed("zsh"), "zsh is not installed");
+    let fixture = Fixture::new("zsh");
+    fixture.file("home/proj/.cdenv.zsh", "FOO=changed\nexport BAR='two words'\ntypeset -ga ARR=(1 'x y')\n\
+                                          greet() { print hello }\nalias ll='ls -l'\nsetopt extendedglob\n");
+    fixture.file("home/proj/sub/.cdenv.zsh", "SUB=x\n");
+
+    let output = fixture.run_zsh(r#"
+FOO=original
+go home/proj/sub
+stack
+print -r -- "$FOO|$BAR|${ARR[2]}|$(greet)|$aliases[ll]|$SUB|$options[extendedglob]"
+go home
+stack
+print -r -- "$FOO|${BAR-unset}|${+ARR}|${+functions[greet]}|${+aliases[ll]}|${SUB-unset}|$options[extendedglob]"
+"#);
+
+    assert_eq!(output, "home/proj/.cdenv.zsh\n\
+                        home/proj/sub/.cdenv.zsh\n\
+                        changed|two words|x y|hello|ls -l|x|on\n\
+                        original|unset|0|0|0|unset|off\n");
+}
+
+#[test]
+fn fish_fixture_is_parsed_completely() {
+    let snapshot = fixture("fish-3.6");
+    assert_eq!(snapshot.dialect, Dialect { shell: Shell::Fish, major: 3, minor: 6, posix: false });
+    assert!(snapshot.unparsed.is_empty(), "{:?}", snapshot.unparsed);
+
+    assert_eq!(snapshot.vars["A"], "set -g A '1' 'x y' 'it\\'s'\n");
+    assert_eq!(snapshot.vars["C"], "set -g C 'a\x01b'\n");
+    assert_eq!(snapshot.vars["D"], "set -g D\n");
+    assert_eq!(snapshot.vars["E"], "set -gx E 'say \"hi\" $HOME'\n");
+    assert_eq!(snapshot.vars["X"], "set -g X 'a\nb'\n");
+    assert_eq!(snapshot.funcs["f"], "# Defined via `source`\nfunction f\n    echo '}\nend'\nend\n");
+    assert_eq!(snapshot.aliases["q"], "abbr -a -- q 'it\\'s'\n");
+    assert_eq!(snapshot.aliases["m"], "abbr -a -- m 'x\\\\y'\n");
+
+    let snapshot = Snapshot::parse("# cdenv fish 3.6\n# cdenv state\npwd /home/user\n");
+    assert!(snapshot.unparsed.is_empty(), "{:?}", snapshot.unparsed);
+    assert_eq!(snapshot.pwd.as_deref(), Some("/home/user"));
+}
+
+#[test]
+fn fish_changes_are_undone_with_fish_statements() {
+    let before = fixture("fish-3.6");
+    let text = fs::read_to_string(format!("{}/tests/fixtures/fish-3.6.txt", env!("CARGO_MANIFEST_DIR")))
+        .unwrap()
+        .replace("var E -gx =say%20%22hi%22%20%24HOME\n", "")
+        .replace("var I -g =3", "var I -g =4 =a%5Cb")
+        .replace("abbr q ", "abbr r%27 ")
+        .replace("func f ", "func g%20h %0A\nfunc f ");
+    let after = Snapshot::parse(&text);
+    assert!(after.unparsed.is_empty(), "{:?}", after.unparsed);
+
+    let rendered = diff(&before, &after).render(&before);
+    for statement in &["set -gx E 'say \"hi\" $HOME'\n", "set -e -g I\nset -g I '3'\n",
+                       "functions -e -- 'g h'\n", "abbr -e -- 'r\\''\n", "abbr -a -- q 'it\\'s'\n",
+                       "c.debug 'remove  r\\'*'\n"] {
+        assert!(rendered.restore.contains(statement), "{:?} not in:\n{}", statement, rendered.restore);
+    }
+    assert!(rendered.output.contains("c.debug 'modify  I'\n"), "{}", rendered.output);
+}
+
+// A session in a real fish. The cdenv file uses set without a scope, see
+// c:safe_source.
+#[test]
+#[ignore = "needs fish"]
+fn fish_session_loads_and_undoes_changes() {
+    assert!(common::installed("fish"), "fish is not installed");
+    let fixture = Fixture::new("fish");
+    fixture.file("home/proj/.cdenv.fish", "set FOO changed\nset -x BAR 'two words'\nset LIST 1 'x y'\n\
+                                           function greet; echo hello; end\nabbr -a ll 'ls -l'\n");
+    fixture.file("home/proj/sub/.cdenv.fish", "set SUB x\n");
+
+    let output = fixture.run_fish(r#"
+set -g FOO original
+go home/proj/sub
+stack
+echo $FOO
+echo $BAR
+echo $LIST[2]
+greet
+abbr --list
+echo $SUB
+set -q -x BAR; and echo exported
+go home
+stack
+echo $FOO
+set -q BAR; or echo BAR unset
+set -q LIST; or echo LIST unset
+functions -q greet; or echo greet unset
+abbr --list | string match -q ll; or echo ll unset
+set -q SUB; or echo SUB unset
+"#);
+
+    assert_eq!(output, "home/proj/.cdenv.fish\nhome/proj/sub/.cdenv.fish\n\
+                        changed\ntwo words\nx y\nhello\nll\nx\nexported\n\
+                        original\nBAR unset\nLIST unset\ngreet unset\nll unset\nSUB unset\n");
+}
+
+#[test]
+fn fish_plans_set_global_lists() {
+    let fixture = Fixture::new("fish-plan");
+    fixture.file("home/proj/.cdenv.fish", "set -g FOO bar\n");
+    let home = fixture.root.join("home").display().to_string();
+    let config = DiscoveryConfig {
+        global: true,
+        reload: false,
+        autoreload: false,
+        tag: 0,
+        file: String::from(".cdenv.fish"),
+        path: String::new(),
+        home: home.clone(),
+        shell: Shell::Fish
+    };
+    let code = discover(&config, &format!("{}/proj", home), &[]).render(&config);
+    let file = fish_quote(&format!("{}/proj/.cdenv.fish", home));
+    assert_eq!(code, format!("set -g CDENV_STACK {}\nset -g __cdenv_unload\nset -g __cdenv_load {}\n", file, file));
+}
+
+#[test]
+fn posix_shell_snapshots_are_parsed() {
+    let snapshot = Snapshot::parse("# cdenv sh\n# cdenv exports\nexport A='1'\nexport B\n\
+                                    # cdenv variables\nA='1'\nC='x\ny'\\''z'\n# cdenv state\npwd /\n");
+    assert_eq!(snapshot.dialect.shell, Shell::Sh);
+    assert!(snapshot.unparsed.is_empty(), "{:?}", snapshot.unparsed);
+    assert_eq!(snapshot.vars["A"], "A='1'\nexport A\n");
+    assert_eq!(snapshot.vars["B"], "export B\n");
+    assert_eq!(snapshot.vars["C"], "C='x\ny'\\''z'\n");
+    assert_eq!(snapshot.pwd.as_deref(), Some("/"));
+}
+
+#[test]
+fn posix_shell_changes_are_undone() {
+    let fixture = Fixture::new("dash");
+    fixture.file("home/proj/.cdenv.sh", "FOO='it'\\''s\ntwo'\nexport BAR=1\nPATH=/proj/bin:$PATH\nunset GONE\n");
+    fixture.file("home/proj/sub/.cdenv.sh", "SUB=x\nexport FOO\n");
+
+    let output = fixture.run_posix(r#"
+GONE=here
+show() { echo "${FOO-unset} ${BAR-unset} ${SUB-unset} ${GONE-unset} ${PATH%%:*}"; export -p | grep -c FOO; }
+before="$(show)"
+go home/proj/sub
+show
+go home
+[ "$(show)" = "$before" ] && echo restored
+"#);
+
+    assert_eq!(output, "it's\ntwo 1 x unset /proj/bin\n1\nrestored\n");
+}
@@ -0,0 +1,26 @@
+# The definitions for the fish snapshot fixture, see README.md.
+set -g X a\nb
+set -g C a\x01b
+set -g A 1 "x y" "it's"
+set -g I 3
+set -gx E 'say "hi" $HOME'
+set -g D
+# A function from a file would name the file and line in the first line of its
+# definition, one that is sourced from a pipe does not.
+printf '%s\n' 'function f' '    echo \'}' 'end\'' 'end' | source
+abbr -a a 'b c'
+abbr -a q "it's"
+abbr -a m 'x\\y'
+
+echo "# cdenv fish $version"
+for name in A C D E I X
+    set -l flags -g
+    set -q -x $name; and set flags -gx
+    echo var $name $flags "="(string escape --style=url -- $$name)
+end
+echo func f (functions -- f | string collect | string escape --style=url)
+set -l names (abbr --list)
+set -l lines (abbr --show)
+for i in (seq (count $names))
+    echo abbr (string escape --style=url -- $names[$i]) (string escape --style=url -- $lines[$i])
+end
@@ -0,0 +1,27 @@
+# The definitions for the snapshot fixtures, see README.md. Pass "posix" as
+# the first argument to capture the output in posix mode.
+X=$'a\nb'
+C=$'a\x01b'
+declare -a A=(1 "x y" "it's")
+declare -A M=([k]=v ["a b"]=c)
+declare -i I=3
+export E='say "hi" $HOME'
+declare D
+f() {
+    cat <<'END'
+}
+END
+}
+alias a='b c'
+alias q="it's"
+alias m=$'x\ny'
+
+if [[ $1 = posix ]]; then
+    set -o posix
+    echo "# cdenv bash ${BASH_VERSINFO[0]}.${BASH_VERSINFO[1]} posix"
+else
+    echo "# cdenv bash ${BASH_VERSINFO[0]}.${BASH_VERSINFO[1]}"
+fi
+declare -p A C D E I M X
+declare -f f
+alias
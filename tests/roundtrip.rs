@@ -0,0 +1,286 @@
+// cdenv - tests/roundtrip.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Property tests for the snapshot parser and the restore files. Each case
+// generates a random environment and a random cdenv file that changes it.
+// Real bash loads and unloads the file, and the environment must be the same
+// afterwards. Every snapshot bash produces along the way must be parsed
+// completely.
+//
+// CDENV_TEST_SEED and CDENV_TEST_CASES reproduce a failing run or run more
+// cases.
+
+extern crate cdenv;
+
+mod common;
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cdenv::Snapshot;
+use common::Fixture;
+
+const OPTIONS: &[&str] = &["extglob", "dotglob", "globstar", "nocaseglob"];
+
+// Characters for values, including everything that needs quoting.
+const CHARS: &[&str] = &["a", "b", "Z", "0", "7", "_", " ", "  ", "'", "\"", "\\", "$", "`", "!",
+                         "\n", "\t", "\r", "\x01", "\x1b", "\x7f", "{", "}", "(", ")", "[", "]",
+                         "=", "#", ";", "*", "~", "ä", "€", "\\\"", "\\\n", "\"\n", ")\n"];
+
+// Lines for here-documents, some of them look like the end of a function or
+// like other parts of a snapshot.
+const LINES: &[&str] = &["}", "  }", "{", "declare -- X=\"y\"", "declare -a A=(", ")", "alias a='b'",
+                         "f ()", "# cdenv state", "'", "\"", "\\", "$x `y`", "EOFX", ""];
+
+// A small xorshift generator, so that a seed reproduces a run.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+
+    fn text(&mut self, max: usize) -> String {
+        (0..self.below(max + 1)).map(|_| self.pick(CHARS)).collect()
+    }
+}
+
+// Quote a string with $'...' using escapes for everything but letters,
+// digits and spaces.
+fn ansi_c(value: &str) -> String {
+    let mut result = String::from("$'");
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b' ' {
+            result.push(byte as char);
+        } else {
+            result.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    result.push('\'');
+    result
+}
+
+const VARIABLES: &[&str] = &["pt_v0", "pt_v1", "pt_v2", "pt_v3", "pt_v4", "pt_v5", "pt_v6", "pt_v7"];
+const FUNCTIONS: &[&str] = &["pt_f0", "pt_f1", "pt-f2", "pt.f3", "pt:f4", "pt+f5", "pt/f6", "ptä7",
+                             "pt%f8", "pt#f9"];
+const ALIASES: &[&str] = &["pt_a0", "pt-a1", "pt.a2", "pt+a3", "pt@a4", "pt%a5", "pt,a6", "pt:a7",
+                           "pt*a8", "ptä9", "pt!a10", "pt[a11", "pt{a12", "pt#a13"];
+
+fn variable(random: &mut Random, name: &str) -> String {
+    match random.below(7) {
+        0 => format!("declare -g {}\n", name),
+        1 => format!("declare -gx {}={}\n", name, ansi_c(&random.text(8))),
+        2 => format!("declare -gi {}={}\n", name, random.next() as i32),
+        3 => {
+            let mut code = format!("declare -ga {}=()\n", name);
+            for _ in 0..random.below(4) {
+                code.push_str(&format!("{}[{}]={}\n", name, random.below(20), ansi_c(&random.text(6))));
+            }
+            code
+        },
+        4 => {
+            let mut code = format!("declare -gA {}=()\n", name);
+            for _ in 0..random.below(4) {
+                let key = format!("k{}", random.text(4));
+                code.push_str(&format!("{}[{}]={}\n", name, ansi_c(&key), ansi_c(&random.text(6))));
+            }
+            code
+        },
+        _ => format!("declare -g {}={}\n", name, ansi_c(&random.text(12)))
+    }
+}
+
+fn function(random: &mut Random, name: &str) -> String {
+    let lines: Vec<&str> = (0..random.below(4)).map(|_| random.pick(LINES)).collect();
+    match random.below(4) {
+        0 => format!("function {}() {{\n    cat <<'EOF'\n{}\nEOF\n}}\n", name, lines.join("\n")),
+        1 => format!("{}() {{\n    cat <<-EOF\n\t{}\n\tEOF\n    echo {}\n}}\n", name,
+                     lines.join("\n\t").replace(['$', '`', '\\'], "_"), ansi_c(&random.text(6))),
+        2 => {
+            // A double quoted string that spans several lines.
+            let text = lines.join("\n").replace(['"', '$', '`', '\\'], "_");
+            format!("{}() {{\n    echo \"{}\"\n}}\n", name, text)
+        },
+        _ => format!("function {} {{ echo {}; }}\n", name, ansi_c(&random.text(8)))
+    }
+}
+
+fn alias(random: &mut Random, name: &str) -> String {
+    format!("alias '{}'={}\n", name, ansi_c(&random.text(10)))
+}
+
+// Return the code that defines a random environment and the code that
+// changes it.
+fn generate(random: &mut Random) -> (String, String) {
+    let mut before = String::new();
+    let mut file = String::new();
+
+    type Define = fn(&mut Random, &str) -> String;
+    let kinds: [(&[&str], Define, &str); 3] = [
+        (VARIABLES, variable, "unset -v"),
+        (FUNCTIONS, function, "unset -f --"),
+        (ALIASES, alias, "unalias --")
+    ];
+
+    for (names, define, remove) in kinds.iter() {
+        for name in names.iter() {
+            let exists = random.chance(50);
+            if exists {
+                before.push_str(&define(random, name));
+            }
+            match random.below(3) {
+                0 => {},
+                1 if exists => file.push_str(&format!("{} '{}'\n", remove, name)),
+                _ => {
+                    if exists && *remove == "unset -v" {
+                        // The type of a variable cannot always be changed.
+                        file.push_str(&format!("unset -v {}\n", name));
+                    }
+                    file.push_str(&define(random, name));
+                }
+            }
+        }
+    }
+
+    for option in OPTIONS {
+        if random.chance(50) {
+            before.push_str(&format!("shopt -s {}\n", option));
+        }
+        if random.chance(30) {
+            file.push_str(&format!("shopt -{} {}\n", if random.chance(50) { "s" } else { "u" }, option));
+        }
+    }
+
+    (before, file)
+}
+
+// Print the generated names in a fixed order, so that two dumps can be
+// compared as text.
+fn dump() -> String {
+    let mut code = String::from("dump() {\n");
+    for name in VARIABLES {
+        code.push_str(&format!("    declare -p {} 2>/dev/null\n", name));
+    }
+    for name in FUNCTIONS {
+        code.push_str(&format!("    declare -f '{}' 2>/dev/null\n", name));
+    }
+    for name in ALIASES {
+        code.push_str(&format!("    alias '{}' 2>/dev/null\n", name));
+    }
+    code.push_str(&format!("    shopt {}\n", OPTIONS.join(" ")));
+    code.push_str("    return 0\n}\n");
+    code
+}
+
+// Check that a snapshot of the whole environment is parsed completely and
+// yields the same names as bash itself.
+fn check_snapshot(fixture: &Fixture, name: &str) -> Result<(), String> {
+    let text = fs::read_to_string(fixture.root.join(name)).unwrap();
+    let snapshot = Snapshot::parse(&text);
+    if !snapshot.unparsed.is_empty() {
+        return Err(format!("{}: unparsed lines: {:?}", name, snapshot.unparsed));
+    }
+
+    let expect = |file: &str| -> HashSet<String> {
+        fixture.lines(&format!("{}.{}", name, file)).into_iter().collect()
+    };
+    let keys = |map: &std::collections::HashMap<String, String>| -> HashSet<String> {
+        map.keys().cloned().collect()
+    };
+    for (file, found) in &[("vars", keys(&snapshot.vars)), ("funcs", keys(&snapshot.funcs)),
+                           ("aliases", keys(&snapshot.aliases))] {
+        let expected = expect(file);
+        // Variables that are declared without a value are not listed by
+        // compgen.
+        let missing: Vec<&String> = expected.difference(found).collect();
+        let extra: Vec<&String> = found.difference(&expected)
+            .filter(|name| !snapshot.vars.get(*name).map(|v| !v.contains('=')).unwrap_or(false))
+            .collect();
+        if !missing.is_empty() || !extra.is_empty() {
+            return Err(format!("{}: {} missing {:?}, extra {:?}", name, file, missing, extra));
+        }
+    }
+    Ok(())
+}
+
+fn run_case(random: &mut Random) -> Result<(), String> {
+    let (before, file) = generate(random);
+
+    let fixture = Fixture::new("roundtrip");
+    fixture.file("home/proj/.cdenv.sh", &file);
+
+    // Snapshot the whole environment the same way cdenv.sh does, together
+    // with the names that bash knows of.
+    let snapshot = r#"
+snapshot() {
+    c:snapshot >| "$CDENV_TEST_ROOT/$1"
+    compgen -v >| "$CDENV_TEST_ROOT/$1.vars"
+    compgen -A function >| "$CDENV_TEST_ROOT/$1.funcs"
+    compgen -a >| "$CDENV_TEST_ROOT/$1.aliases"
+}
+"#;
+
+    let output = fixture.run(&format!(
+        "{}{}{}dump; echo ---\nsnapshot before\ngo home/proj\nsnapshot loaded\ngo home\ndump\n",
+        before, dump(), snapshot));
+
+    let context = || format!("environment:\n{}\ncdenv file:\n{}", before, file);
+
+    let mut parts = output.split("---\n");
+    let (a, b) = (parts.next().unwrap(), parts.next().unwrap());
+    if a != b {
+        return Err(format!("environment not restored\nbefore:\n{}\nafter:\n{}\n{}", a, b, context()));
+    }
+
+    for name in &["before", "loaded"] {
+        check_snapshot(&fixture, name).map_err(|err| format!("{}\n{}", err, context()))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn environments_survive_load_and_unload() {
+    let seed = match env::var("CDENV_TEST_SEED") {
+        Ok(seed) => seed.parse().unwrap(),
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64 | 1
+    };
+    let cases: usize = env::var("CDENV_TEST_CASES").map(|x| x.parse().unwrap()).unwrap_or(20);
+
+    let mut random = Random(seed);
+    for case in 0..cases {
+        if let Err(err) = run_case(&mut random) {
+            panic!("case {} failed, CDENV_TEST_SEED={}\n{}", case, seed, err);
+        }
+    }
+}
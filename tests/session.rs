@@ -0,0 +1,739 @@
+// cdenv - tests/session.rs
+//
+// Copyright (C) 2021  Lars Gustäbel <lars@gustaebel.de>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// End-to-end tests that walk through directory trees in a real bash session.
+
+mod common;
+
+use common::Fixture;
+
+// A cdenv file that records in the trace file each time it is sourced.
+fn traced(name: &str) -> String {
+    format!("echo {} >> \"$CDENV_TEST_ROOT/trace\"\n", name)
+}
+
+#[test]
+fn changes_are_undone() {
+    let fixture = Fixture::new("undone");
+    fixture.file("home/proj/.cdenv.sh", r#"
+FOO=changed
+export BAR="two words"
+greet() { echo hello; }
+alias ll='ls -l'
+shopt -s extglob
+"#);
+
+    let output = fixture.run(r#"
+FOO=original
+go home/proj
+echo "$FOO|$BAR|$(greet)|${BASH_ALIASES[ll]}"
+shopt -q extglob && echo extglob
+declare -p BAR
+go home
+echo "$FOO|${BAR-unset}|$(type -t greet)|${BASH_ALIASES[ll]-unset}"
+shopt -q extglob || echo noextglob
+"#);
+
+    assert_eq!(output, "changed|two words|hello|ls -l\n\
+                        extglob\n\
+                        declare -x BAR=\"two words\"\n\
+                        original|unset||unset\n\
+                        noextglob\n");
+}
+
+#[test]
+fn large_snapshots_do_not_block_wait() {
+    let fixture = Fixture::new("wait");
+    fixture.file("home/proj/.cdenv.sh", "sleep 0.1 &\nwait\nFOO=changed\n");
+
+    // The snapshot is much larger than a pipe buffer. The watchdog is not a
+    // job of the shell, so the wait in the cdenv file does not wait for it.
+    let output = fixture.run(r#"
+BIG=$(head -c 200000 /dev/zero | tr '\0' x)
+(sleep 30 && kill -KILL $$) >/dev/null 2>&1 & watchdog=$!
+disown
+go home/proj
+kill $watchdog
+echo "$FOO"
+"#);
+
+    assert_eq!(output, "changed\n");
+}
+
+#[test]
+fn restore_file_lives_while_loaded() {
+    let fixture = Fixture::new("restore");
+    fixture.file("home/proj/.cdenv.sh", "FOO=changed\n");
+
+    let output = fixture.run(r#"
+FOO=original
+go home/proj
+restore="$(c:store path "$HOME/proj/.cdenv.sh")"
+[[ $(stat -c %a "$restore") = 600 ]] && echo private
+grep '^declare' "$restore"
+go home
+[[ -e $restore ]] || echo removed
+"#);
+
+    assert_eq!(output, "private\ndeclare -g FOO=\"original\"\nremoved\n");
+}
+
+#[test]
+fn stack_follows_the_directory() {
+    let fixture = Fixture::new("stack");
+    fixture.file("home/proj/.cdenv.sh", "");
+    fixture.file("home/proj/sub/.cdenv.sh", "");
+    fixture.file("home/proj/sub/deeper/.cdenv.sh", "");
+    fixture.file("outside/.cdenv.sh", "");
+
+    let output = fixture.run(r#"
+go home/proj/sub/deeper; stack; echo
+go home/proj; stack; echo
+go outside; stack
+"#);
+
+    assert_eq!(output, "home/proj/.cdenv.sh\n\
+                        home/proj/sub/.cdenv.sh\n\
+                        home/proj/sub/deeper/.cdenv.sh\n\
+                        \n\
+                        home/proj/.cdenv.sh\n\
+                        \n\
+                        outside/.cdenv.sh\n");
+}
+
+#[test]
+fn common_parents_stay_loaded() {
+    let fixture = Fixture::new("diverge");
+    fixture.file("home/proj/.cdenv.sh", &traced("proj"));
+    fixture.file("home/proj/a/.cdenv.sh", &traced("a"));
+    fixture.file("home/proj/b/.cdenv.sh", &traced("b"));
+    fixture.file("home/proj/b/c/.cdenv.sh", &traced("c"));
+
+    fixture.run("go home/proj/a; go home/proj/b/c; go home/proj/b; go home/proj/a; go home/proj/b/c");
+
+    // Only the files below the point where the stacks diverge are sourced.
+    assert_eq!(fixture.lines("trace"), ["proj", "a", "b", "c", "a", "b", "c"]);
+}
+
+#[test]
+fn divergence_unloads_in_reverse_order() {
+    let fixture = Fixture::new("reverse");
+    fixture.file("home/proj/.cdenv.sh", "X+=p\n");
+    fixture.file("home/proj/a/.cdenv.sh", "X+=a\n");
+    fixture.file("home/proj/a/b/.cdenv.sh", "X+=b\n");
+    fixture.file("home/proj/c/.cdenv.sh", "X+=c\n");
+
+    let output = fixture.run(r#"
+X=
+go home/proj/a/b; echo "$X"
+go home/proj/c; echo "$X"
+go home; echo "[$X]"
+"#);
+
+    assert_eq!(output, "pab\npc\n[]\n");
+}
+
+#[test]
+fn reload_sources_everything_again() {
+    let fixture = Fixture::new("reload");
+    fixture.file("home/proj/.cdenv.sh", &traced("proj"));
+    fixture.file("home/proj/sub/.cdenv.sh", &traced("sub"));
+
+    let output = fixture.run("go home/proj/sub; cdenv reload; stack");
+
+    assert_eq!(fixture.lines("trace"), ["proj", "sub", "proj", "sub"]);
+    assert_eq!(output, "home/proj/.cdenv.sh\nhome/proj/sub/.cdenv.sh\n");
+}
+
+#[test]
+fn changed_files_are_not_reloaded_without_autoreload() {
+    let fixture = Fixture::new("noautoreload");
+    fixture.file("home/proj/.cdenv.sh", &traced("proj"));
+
+    fixture.run(r#"
+go home/proj
+touch -d "@$(( $(date +%s) + 10 ))" "$HOME/proj/.cdenv.sh"
+cdenv update
+"#);
+
+    assert_eq!(fixture.lines("trace"), ["proj"]);
+}
+
+#[test]
+fn autoreload_reloads_changed_files() {
+    let fixture = Fixture::new("autoreload");
+    fixture.rc("CDENV_AUTORELOAD=1");
+    fixture.file("home/proj/.cdenv.sh", &traced("proj"));
+    fixture.file("home/proj/sub/.cdenv.sh", &traced("sub"));
+    fixture.file("home/proj/sub/deeper/.cdenv.sh", &traced("deeper"));
+
+    let output = fixture.run(r#"
+go home/proj/sub/deeper
+cdenv update
+touch -d "@$(( $(date +%s) + 10 ))" "$HOME/proj/sub/.cdenv.sh"
+cdenv update
+cdenv update
+rm "$HOME/proj/sub/deeper/.cdenv.sh"
+cdenv update
+stack
+"#);
+
+    // A changed file is reloaded together with all files after it, a removed
+    // file is unloaded.
+    assert_eq!(fixture.lines("trace"), ["proj", "sub", "deeper", "sub", "deeper"]);
+    assert_eq!(output, "home/proj/.cdenv.sh\nhome/proj/sub/.cdenv.sh\n");
+}
+
+#[test]
+fn global_file_is_loaded_everywhere() {
+    let fixture = Fixture::new("global");
+    fixture.file("libs/lib.sh", &traced("lib"));
+    fixture.file("home/.cdenv.sh", &traced("global"));
+    fixture.file("home/proj/.cdenv.sh", &traced("proj"));
+    fixture.file("outside/.cdenv.sh", &traced("outside"));
+
+    let output = fixture.run("go home; stack; echo; go home/proj; stack; echo; go outside; stack");
+
+    assert_eq!(output, "libs/lib.sh\nhome/.cdenv.sh\n\n\
+                        libs/lib.sh\nhome/.cdenv.sh\nhome/proj/.cdenv.sh\n\n\
+                        libs/lib.sh\nhome/.cdenv.sh\noutside/.cdenv.sh\n");
+    assert_eq!(fixture.lines("trace"), ["lib", "global", "proj", "outside"]);
+}
+
+#[test]
+fn global_file_is_ordinary_without_global() {
+    let fixture = Fixture::new("noglobal");
+    fixture.rc("CDENV_GLOBAL=0");
+    fixture.file("libs/lib.sh", &traced("lib"));
+    fixture.file("home/.cdenv.sh", &traced("global"));
+    fixture.file("home/proj/.cdenv.sh", &traced("proj"));
+    fixture.file("outside/.cdenv.sh", &traced("outside"));
+
+    let output = fixture.run("go home/proj; stack; echo; go outside; stack");
+
+    assert_eq!(output, "libs/lib.sh\nhome/.cdenv.sh\nhome/proj/.cdenv.sh\n\n\
+                        libs/lib.sh\noutside/.cdenv.sh\n");
+    assert_eq!(fixture.lines("trace"), ["lib", "global", "proj", "outside"]);
+}
+
+#[test]
+fn unusual_names_are_undone() {
+    let fixture = Fixture::new("names");
+    fixture.file("home/proj/.cdenv.sh", r#"
+function docker+ { echo new; }
+function k8s/ctx { echo new; }
+function ä { echo new; }
+function a=b { echo new; }
+function -x { echo new; }
+unset -f -- -y
+alias ..='cd ../..'
+alias g.st='git status'
+alias 'a*b'='new'
+alias -- -x='new'
+unalias -- -y
+alias ä='new'
+"#);
+    // Files that the restore statements for a*b match as a pattern, the
+    // restore file is read in the home directory.
+    fixture.file("home/ab=old", "");
+    fixture.file("home/ab", "");
+
+    let output = fixture.run(r#"
+function docker+ { echo old; }
+function -y { echo old; }
+alias ..='cd ..'
+alias -- -y='old'
+alias 'a*b'='old'
+dump() { declare -f; alias; }
+dump > "$CDENV_TEST_ROOT/before"
+go home/proj
+dump > "$CDENV_TEST_ROOT/loaded"
+go home
+dump > "$CDENV_TEST_ROOT/after"
+cmp "$CDENV_TEST_ROOT/before" "$CDENV_TEST_ROOT/after" && echo same
+cmp -s "$CDENV_TEST_ROOT/before" "$CDENV_TEST_ROOT/loaded" || echo changed
+"#);
+
+    assert_eq!(output, "same\nchanged\n");
+}
+
+#[test]
+fn serve_answers_list_and_compare() {
+    let fixture = Fixture::new("serve");
+    fixture.rc("CDENV_SERVE=1\nCDENV_AUTORELOAD=1");
+    fixture.file("home/proj/.cdenv.sh", r#"
+FOO=changed
+greet() { echo hello; }
+"#);
+
+    // Nothing but the coprocess can answer while CDENV_EXEC is broken.
+    let output = fixture.run(r#"
+FOO=original
+c:serving && echo serving
+exec=$CDENV_EXEC
+CDENV_EXEC=false
+go home/proj
+echo "$FOO|$(greet)"
+CDENV_EXEC=$exec
+go home
+echo "$FOO|$(type -t greet)"
+"#);
+
+    assert_eq!(output, "serving\nchanged|hello\noriginal|\n");
+}
+
+#[test]
+fn serve_keeps_extdebug() {
+    let fixture = Fixture::new("serve-extdebug");
+    fixture.rc("CDENV_SERVE=1");
+    fixture.file("home/proj/.cdenv.sh", "FOO=changed\n");
+    let output = fixture.run(r#"
+shopt -s extdebug
+go home/proj
+shopt -q extdebug && echo on
+go home
+shopt -u extdebug
+go home/proj
+shopt -q extdebug || echo off
+"#);
+    assert_eq!(output, "on\noff\n");
+}
+
+#[test]
+fn serve_survives_odd_requests() {
+    let fixture = Fixture::new("serve-odd");
+    fixture.rc("CDENV_SERVE=1");
+    fixture.file("home/proj/.cdenv.sh", "FOO=changed\n");
+    fixture.file("home/proj/a\nb\\n/.keep", "");
+
+    // A directory with a newline and a backslash in its name, then requests
+    // that the server cannot answer.
+    let output = fixture.run(r#"
+FOO=original
+exec=$CDENV_EXEC
+CDENV_EXEC=false
+go $'home/proj/a\nb\\n'
+echo "$FOO"
+stack
+CDENV_EXEC=$exec
+go home
+echo "$FOO"
+c:request compare --file=x --store=y --input=missing missing && eval "$__cdenv_code" 2>&1
+printf 'x\n' >&"${CDENV_SERVER[1]}"
+read -r -u "${CDENV_SERVER[0]}" count && read -r -u "${CDENV_SERVER[0]}" line && echo "$count $line"
+c:serving && echo serving
+"#);
+
+    assert_eq!(output, "changed\n\
+                        home/proj/.cdenv.sh\n\
+                        original\n\
+                        ERROR: cdenv serve: unknown snapshot \"missing\"\n\
+                        1 c.err 'cdenv serve: invalid request \"x\"'\n\
+                        serving\n");
+}
+
+#[test]
+fn builtin_lists_the_same_as_the_executable() {
+    // The shared library is built next to the deps directory with the test
+    // executable, but only together with the workspace.
+    let exe = std::env::current_exe().unwrap();
+    let library = exe.parent().unwrap().parent().unwrap().join("libcdenv_core.so");
+    if !library.exists() {
+        return;
+    }
+
+    let fixture = Fixture::new("builtin");
+    fixture.file("home/.cdenv.sh", "");
+    fixture.file("outside/.cdenv.sh", "");
+    fixture.file("outside/proj/.cdenv.sh", "");
+    fixture.file("libs/lib.sh", "");
+
+    // HOME is changed in the shell, which the environment of the shell
+    // process does not see.
+    let script = r#"
+HOME=$CDENV_TEST_ROOT/outside
+go outside/proj; stack; echo
+go home; stack
+"#;
+    let exec = fixture.run(script);
+    fixture.rc(&format!("CDENV_BUILTIN={}", library.display()));
+    let builtin = fixture.run(&format!("{}enable | grep -c cdenv_core\n", script));
+
+    assert_eq!(exec, "libs/lib.sh\noutside/.cdenv.sh\noutside/proj/.cdenv.sh\n\nlibs/lib.sh\noutside/.cdenv.sh\nhome/.cdenv.sh\n");
+    assert_eq!(builtin, format!("{}1\n", exec));
+}
+
+#[test]
+fn builtin_compares_inside_the_shell() {
+    let exe = std::env::current_exe().unwrap();
+    let library = exe.parent().unwrap().parent().unwrap().join("libcdenv_core.so");
+    if !library.exists() {
+        return;
+    }
+
+    let fixture = Fixture::new("builtin-compare");
+    fixture.rc(&format!("CDENV_BUILTIN={}", library.display()));
+    fixture.file("home/proj/.cdenv.sh", "FOO=changed\ngreet() { echo hello; }\necho loaded\n");
+
+    // Nothing but the builtin can take and compare the snapshots while
+    // CDENV_EXEC is broken, and the snapshots do not fit into a pipe buffer.
+    let output = fixture.run(r#"
+FOO=original
+BIG=$(head -c 200000 /dev/zero | tr '\0' x)
+exec=$CDENV_EXEC
+CDENV_EXEC=false
+go home/proj
+echo "$FOO|$(greet)"
+CDENV_EXEC=$exec
+go home
+echo "$FOO|$(type -t greet)|${#BIG}"
+cdenv_core compare x before after 2>&1 || echo "status $?"
+cdenv_core nope 2>/dev/null || echo "status $?"
+"#);
+
+    assert_eq!(output, "loaded\n\
+                        changed|hello\n\
+                        original||200000\n\
+                        cdenv_core: unknown snapshot \"before\"\n\
+                        status 1\n\
+                        status 2\n");
+}
+
+#[test]
+fn log_records_loads_and_changes() {
+    let fixture = Fixture::new("log");
+    fixture.file("home/proj/.cdenv.sh", "FOO=changed\n");
+
+    let output = fixture.run(r#"
+go home/proj
+go home
+cdenv log --file "$HOME/proj" | awk '{ print $3, $4, $5 }' | sed "s|$CDENV_TEST_ROOT/||"
+cdenv log --pid 1 | wc -l
+cdenv log --since yesterday 2>&1
+echo "status $?"
+"#);
+
+    assert_eq!(output, "load home/proj/.cdenv.sh \n\
+                        add home/proj/.cdenv.sh FOO\n\
+                        unload home/proj/.cdenv.sh \n\
+                        0\n\
+                        error: Invalid value for '--since <since>': invalid time \"yesterday\"\n\
+                        status 2\n");
+}
+
+#[test]
+fn redacted_values_stay_out_of_the_restore_file() {
+    let fixture = Fixture::new("redact");
+    fixture.rc("CDENV_REDACT='^sk-'");
+    fixture.file("home/proj/.cdenv.sh", "KEY=changed\nOTHER=changed\n");
+    let output = fixture.run(r#"
+KEY=sk-secret
+OTHER=plain
+go home/proj
+cat "$XDG_RUNTIME_DIR"/cdenv/$$/* | grep -c sk-secret
+cat "$XDG_RUNTIME_DIR"/cdenv/$$/* | grep ^declare
+go home
+echo "$KEY|$OTHER"
+CDENV_REDACT='('
+go home/proj 2>&1
+go home
+echo "$KEY|$OTHER"
+"#);
+    assert_eq!(output, "0\n\
+                        declare -g OTHER=\"plain\"\n\
+                        sk-secret|plain\n\
+                        ERROR: invalid CDENV_REDACT pattern \"(\", nothing is redacted\n\
+                        sk-secret|plain\n");
+}
+
+#[test]
+fn gc_removes_only_dead_shells_of_this_host() {
+    let fixture = Fixture::new("gc");
+    let output = fixture.run(r#"
+cache="$XDG_RUNTIME_DIR/cdenv"
+host="$(cat /proc/sys/kernel/hostname)"
+mkdir "$cache/999999999" "$cache/999999998" "$cache/999999997"
+printf '1\t%s' "$host" > "$cache/999999999/starttime"
+printf '1\tother-host' > "$cache/999999998/starttime"
+cdenv gc
+ls "$cache" | sed "s/^$$\$/self/" | sort
+"#);
+    // 999999997 has no stamp like the directories of older versions.
+    assert_eq!(output, "999999998\nself\n");
+}
+
+#[test]
+fn directories_of_other_hosts_are_reported() {
+    let fixture = Fixture::new("claim");
+    fixture.file("home/proj/.cdenv.sh", "FOO=changed\n");
+    let output = fixture.run(r#"
+dir="$XDG_RUNTIME_DIR/cdenv/$$"
+printf '1\tother-host' > "$dir/starttime"
+PROMPT_COMMAND=()
+source "$CDENV_TEST_SH" "" 2>&1 | sed "s|$dir|DIR|"
+cat "$dir/starttime"; echo
+"#);
+    assert_eq!(output, "ERROR: unable to create the restore directory: DIR belongs to a shell on other-host, \
+                        cdenv is not activated\n1\tother-host\n");
+}
+
+#[test]
+fn status_shows_what_each_layer_changed() {
+    let fixture = Fixture::new("status");
+    fixture.file("home/proj/.cdenv.sh", "FOO=1\ngreet() { :; }\n");
+    fixture.file("home/proj/sub/.cdenv.sh", "BAR=2\nalias ll=ls\nFOO=2\n");
+    let output = fixture.run(r#"
+go home/proj/sub
+cdenv status -v | sed -e "s|$CDENV_TEST_ROOT/||" -e "s|loaded .*|loaded|"
+"#);
+    assert_eq!(output, "home/proj/.cdenv.sh\n    loaded\n\
+                        \x20   variables  1 added, 0 modified, 0 removed\n        + FOO\n\
+                        \x20   functions  1 added, 0 modified, 0 removed\n        + greet\n\
+                        home/proj/sub/.cdenv.sh\n    loaded\n\
+                        \x20   variables  1 added, 1 modified, 0 removed\n        + BAR\n        ~ FOO\n\
+                        \x20   aliases    1 added, 0 modified, 0 removed\n        + ll\n");
+}
+
+#[test]
+fn blame_and_why_name_the_layer() {
+    let fixture = Fixture::new("blame");
+    fixture.file("home/proj/.cdenv.sh", "FOO=1\ngreet() { :; }\n");
+    fixture.file("home/proj/sub/.cdenv.sh", "FOO=2\nalias ll=ls\n");
+    let output = fixture.run(r#"
+FOO=0
+go home/proj/sub
+cdenv blame | sed "s|$CDENV_TEST_ROOT/||"
+cdenv why FOO | sed "s|$CDENV_TEST_ROOT/||"
+cdenv why NOPE 2>&1 || echo "status $?"
+"#);
+    assert_eq!(output, "variable  FOO                       modified  home/proj/sub/.cdenv.sh\n\
+                        function  greet                     added     home/proj/.cdenv.sh\n\
+                        alias     ll                        added     home/proj/sub/.cdenv.sh\n\
+                        variable FOO\n\
+                        \x20 modified by home/proj/.cdenv.sh\n\
+                        \x20   before: declare -g FOO=\"0\"\n\
+                        \x20   after:  declare -g FOO=\"1\"\n\
+                        \x20 modified by home/proj/sub/.cdenv.sh\n\
+                        \x20   before: declare -g FOO=\"1\"\n\
+                        \x20   after:  declare -g FOO=\"2\"\n\
+                        \x20 current:  declare -g FOO=\"2\"\n\
+                        NOPE has not been changed by any loaded cdenv file\n\
+                        status 1\n");
+}
+
+#[test]
+fn shadowing_names_both_definitions() {
+    let fixture = Fixture::new("shadow");
+    fixture.rc("CDENV_SHADOW_LEVEL=0");
+    fixture.file("home/proj/.cdenv.sh", "FOO=1\ngreet() { echo outer; }\nalias ll='ls -l'\n");
+    fixture.file("home/proj/sub/.cdenv.sh", "\ngreet() { echo inner; }\nunalias ll\n");
+    let output = fixture.run(r#"
+go home/proj/sub 2>&1 | sed "s|$CDENV_TEST_ROOT/||g"
+"#);
+    assert_eq!(output, "WARNING: function greet from home/proj/.cdenv.sh:2 is redefined by home/proj/sub/.cdenv.sh:2\n\
+                        WARNING: alias ll from home/proj/.cdenv.sh:3 is removed by home/proj/sub/.cdenv.sh\n");
+}
+
+#[test]
+fn restore_store_errors_are_reported() {
+    let fixture = Fixture::new("store-errors");
+    fixture.file("blocked", "");
+    let output = fixture.run(r#"
+"$CDENV_EXEC" store --dir="$CDENV_TEST_ROOT/blocked/x" path /a.sh 2>&1 | sed "s|$CDENV_TEST_ROOT/||"
+printf '' | "$CDENV_EXEC" compare --store="$CDENV_TEST_ROOT/blocked/x" --file=/a.sh /dev/null 2>&1 | sed "s|$CDENV_TEST_ROOT/||"
+"#);
+    assert_eq!(output, "unable to access the restore file: Not a directory (os error 20)\n\
+                        unable to write the restore file for /a.sh: Not a directory (os error 20)\n");
+}
+
+#[test]
+fn invalid_shadow_level_is_a_usage_error() {
+    let fixture = Fixture::new("shadow-level");
+    fixture.rc("CDENV_SHADOW_LEVEL=x");
+    fixture.file("home/proj/.cdenv.sh", "FOO=changed\n");
+    let output = fixture.run("go home/proj 2>&1");
+    assert_eq!(output, "error: Invalid value for '--shadow <shadow>': invalid number \"x\"\n");
+}
+
+#[test]
+fn edit_opens_the_definition() {
+    let fixture = Fixture::new("edit");
+    fixture.rc("CDENV_AUTORELOAD=1");
+    fixture.file("home/proj/.cdenv.sh", "# greeting\n\ngreet() { :; }\nFOO=1\neval 'HIDDEN=1'\n");
+    let output = fixture.run(r#"
+EDITOR=echo
+go home/proj
+cdenv edit greet | sed "s|$CDENV_TEST_ROOT/||"
+cdenv edit FOO | sed "s|$CDENV_TEST_ROOT/||"
+cdenv locate FOO | sed "s|$CDENV_TEST_ROOT/||"
+cdenv edit HIDDEN 2>&1 | sed "s|$CDENV_TEST_ROOT/||"
+cdenv edit NOPE 2>&1
+cdenv edit NOPE 2>/dev/null || echo "status $?"
+"#);
+    assert_eq!(output, "+3 home/proj/.cdenv.sh\n\
+                        +4 home/proj/.cdenv.sh\n\
+                        home/proj/.cdenv.sh:4\n\
+                        definition of HIDDEN not found in home/proj/.cdenv.sh\n\
+                        no such variable / function / alias: NOPE\n\
+                        status 1\n");
+}
+
+#[test]
+fn leaks_are_reported_and_flagged() {
+    let fixture = Fixture::new("leaks");
+    fixture.file("home/proj/.cdenv.sh", "cd ..\nsleep 10 &\nreadonly FIXED=1\n");
+    let output = fixture.run(r#"
+go home/proj 2>"$CDENV_TEST_ROOT/stderr"
+kill %1
+sed "s|$CDENV_TEST_ROOT/||g" "$CDENV_TEST_ROOT/stderr"
+cdenv status | sed "s|$CDENV_TEST_ROOT/||g" | grep -v loaded
+"#);
+    assert_eq!(output, "LEAK: home/proj/.cdenv.sh: changed the working directory to home\n\
+                        LEAK: home/proj/.cdenv.sh: started 1 background job(s) that keep running after unloading\n\
+                        LEAK: home/proj/.cdenv.sh: made FIXED readonly, it cannot be restored\n\
+                        home/proj/.cdenv.sh\n\
+                        \x20   leaked     changed the working directory to home\n\
+                        \x20   leaked     started 1 background job(s) that keep running after unloading\n\
+                        \x20   leaked     made FIXED readonly, it cannot be restored\n\
+                        \x20   variables  1 added, 0 modified, 0 removed\n");
+}
+
+#[test]
+fn verify_reports_what_is_not_restored() {
+    let fixture = Fixture::new("verify");
+    fixture.file("home/ok/.cdenv.sh", "FOO=1\ngreet() { :; }\n");
+    fixture.file("home/bad/.cdenv.sh", "readonly FIXED=1\n");
+    let output = fixture.run(r#"
+mkdir "$CDENV_TEST_ROOT/tmp"
+export TMPDIR="$CDENV_TEST_ROOT/tmp"
+cdenv verify ok | sed "s|$CDENV_TEST_ROOT/||"
+cdenv verify bad 2>/dev/null || echo "status $?"
+cdenv verify nope 2>/dev/null || echo "status $?"
+ls -A "$TMPDIR"
+"#);
+    assert_eq!(output, "home/ok: ok\n\
+                        leak: variable FIXED was left behind: (unset) -> declare -gr FIXED=\"1\"\n\
+                        status 1\n\
+                        status 2\n");
+}
+
+#[test]
+fn drift_shows_changes_made_outside_of_layers() {
+    let fixture = Fixture::new("drift");
+    fixture.file("home/proj/.cdenv.sh", "FOO=1\nBAZ=1\n");
+    let output = fixture.run(r#"
+FOO=0
+go home/proj
+cdenv drift
+unset FOO
+BAZ=2
+greet() { :; }
+alias ll=ls
+BAR=1
+cdenv drift
+"#);
+    assert_eq!(output, "+ function go\n\
+                        + function stack\n\
+                        + alias    ll\n\
+                        + function go\n\
+                        + function greet\n\
+                        + function stack\n\
+                        + variable BAR\n\
+                        ~ variable BAZ\n\
+                        - variable FOO\n");
+}
+
+#[test]
+fn plan_shows_what_would_be_loaded() {
+    let fixture = Fixture::new("plan");
+    fixture.file("home/.cdenv.sh", "");
+    fixture.file("home/proj/.cdenv.sh", "");
+    fixture.file("home/other/sub/.cdenv.sh", "");
+    let output = fixture.run(r#"
+go home/proj
+cdenv plan | sed "s|$CDENV_TEST_ROOT/||"
+cdenv plan ../other/sub | sed "s|$CDENV_TEST_ROOT/||"
+"#);
+    assert_eq!(output, "nothing to do\n\
+                        unload home/proj/.cdenv.sh (its directory is left)\n\
+                        load   home/other/sub/.cdenv.sh (its directory is entered)\n");
+}
+
+#[test]
+fn plan_defaults_to_cdenv_sh() {
+    let fixture = Fixture::new("plan-defaults");
+    fixture.file("home/proj/.cdenv.sh", "");
+    let output = fixture.run(r#"
+"$CDENV_EXEC" plan --global=0 --path= --tag=0 "$CDENV_TEST_ROOT/home/proj" | sed "s|$CDENV_TEST_ROOT/||"
+"$CDENV_EXEC" plan --global=0 --path= --tag=x "$CDENV_TEST_ROOT/home/proj" 2>&1 || echo "status $?"
+"#);
+    assert_eq!(output, "load   home/proj/.cdenv.sh (its directory is entered)\n\
+                        error: Invalid value for '--tag <tag>': invalid number \"x\"\n\
+                        status 1\n");
+}
+
+#[test]
+fn exec_runs_a_command_in_the_environment_of_a_directory() {
+    let fixture = Fixture::new("exec");
+    fixture.file("home/proj/.cdenv.sh", "export FOO=1\nBAR=2\necho loaded\n");
+    let output = fixture.run(r#"
+mkdir "$CDENV_TEST_ROOT/tmp"
+export TMPDIR="$CDENV_TEST_ROOT/tmp"
+cdenv exec proj -- sh -c 'echo "${FOO-unset} ${BAR-unset} ${PWD#$CDENV_TEST_ROOT/}"' 2>&1
+cdenv exec proj -- sh -c 'exit 3' 2>/dev/null || echo "status $?"
+cdenv exec nope -- true 2>/dev/null || echo "status $?"
+echo "${FOO-unset}"
+ls -A "$TMPDIR"
+"#);
+    assert_eq!(output, "loaded\n\
+                        1 unset home\n\
+                        status 3\n\
+                        status 2\n\
+                        unset\n");
+}
+
+#[test]
+fn export_leaves_out_functions() {
+    let fixture = Fixture::new("export-functions");
+    fixture.file("home/proj/.cdenv.sh", "greet() { echo hello; }\nexport -f greet\nexport FOO=1\n");
+    let output = fixture.run(r#"
+mkdir "$CDENV_TEST_ROOT/tmp"
+export TMPDIR="$CDENV_TEST_ROOT/tmp"
+cdenv export --format=export proj
+"#);
+    assert_eq!(output, "export FOO='1'\n");
+}
+
+#[test]
+fn export_is_the_same_inside_and_outside() {
+    let fixture = Fixture::new("export");
+    fixture.file("home/proj/.cdenv.sh", "export FOO='two words'\nexport PATH=\"/opt/proj/bin:$PATH\"\nBAR=local\necho loaded\n");
+    let output = fixture.run(r#"
+mkdir "$CDENV_TEST_ROOT/tmp"
+export TMPDIR="$CDENV_TEST_ROOT/tmp"
+cdenv export proj 2>&1
+cdenv export --format=json proj 2>/dev/null
+go home/proj >/dev/null
+cdenv export . 2>/dev/null
+cdenv export --format=json . 2>/dev/null
+ls -A "$TMPDIR"
+"#);
+    let expected = "FOO=\"two words\"\n\
+                    PATH=\"/opt/proj/bin:/usr/local/bin:/usr/bin:/bin\"\n\
+                    {\"FOO\":\"two words\",\"PATH\":\"/opt/proj/bin:/usr/local/bin:/usr/bin:/bin\"}\n";
+    assert_eq!(output, format!("loaded\n{}{}", expected, expected));
+}